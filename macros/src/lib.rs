@@ -0,0 +1,389 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Literal, TokenStream as TokenStream2};
+use quote::{quote, format_ident};
+use syn::{
+    Attribute, Error, FnArg, GenericArgument, Ident,
+    ItemTrait, PathArguments, ReturnType, TraitItem, Type,
+};
+
+/// Methods generated on the client wrapper; RPC methods
+/// can not use these names.
+const RESERVED_METHODS: [&str; 3] = ["new", "from_client", "rpc"];
+
+///
+/// Generates RPC bindings from a trait of async methods. Each method
+/// must take `&self` and a single request argument and return
+/// `Result<Resp, RpcResponseError>`. For a trait named `Service` the
+/// macro produces:
+///
+/// - `ServiceOps` - an op enum implementing `TryFrom<u32>`, one
+///   variant per method, numbered in declaration order
+/// - `ServiceClient` - a typed wrapper over `RpcClient<ServiceOps>`
+///   with one method per RPC
+/// - `Service` - the server-side trait (native only) receiving the
+///   connection `Arc<RpcContext>` ahead of the request
+/// - `ServiceHandler<T>` - an `RpcHandler<ServiceOps>` dispatching
+///   requests to a `T : Service` implementation (native only)
+///
+/// The generated code only refers to items of `workflow_rpc`.
+/// The attribute takes no arguments.
+///
+#[proc_macro_attribute]
+pub fn rpc(attr: TokenStream, item: TokenStream) -> TokenStream {
+    match rpc_impl(attr.into(), item.into()) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn rpc_impl(attr: TokenStream2, item: TokenStream2) -> syn::Result<TokenStream2> {
+    if !attr.is_empty() {
+        return Err(Error::new_spanned(attr, "#[rpc] does not take arguments"));
+    }
+    let item = syn::parse2::<ItemTrait>(item)?;
+    expand(item)
+}
+
+struct Method {
+    attrs : Vec<Attribute>,
+    name : Ident,
+    variant : Ident,
+    req : Type,
+    resp : Type,
+}
+
+fn expand(item: ItemTrait) -> syn::Result<TokenStream2> {
+    let vis = &item.vis;
+    let attrs = &item.attrs;
+    let name = &item.ident;
+    let ops = format_ident!("{}Ops", name);
+    let client = format_ident!("{}Client", name);
+    let handler = format_ident!("{}Handler", name);
+
+    let methods = item.items.iter().map(parse_method).collect::<syn::Result<Vec<_>>>()?;
+    if methods.is_empty() {
+        return Err(Error::new_spanned(name, "#[rpc] traits must declare at least one method"));
+    }
+
+    let variants = methods.iter().enumerate().map(|(index, method)| {
+        let variant = &method.variant;
+        let index = Literal::u32_unsuffixed(index as u32);
+        quote! { #variant = #index, }
+    });
+
+    let conversions = methods.iter().enumerate().map(|(index, method)| {
+        let variant = &method.variant;
+        let index = Literal::u32_unsuffixed(index as u32);
+        quote! { #index => Ok(#ops::#variant), }
+    });
+
+    let server_methods = methods.iter().map(|Method { attrs, name, req, resp, .. }| {
+        quote! {
+            #(#attrs)*
+            async fn #name(
+                &self,
+                ctx : ::std::sync::Arc<::workflow_rpc::asynchronous::server::RpcContext>,
+                req : #req
+            ) -> ::std::result::Result<#resp, ::workflow_rpc::asynchronous::error::RpcResponseError>;
+        }
+    });
+
+    let client_methods = methods.iter().map(|Method { attrs, name, variant, req, resp }| {
+        quote! {
+            #(#attrs)*
+            pub async fn #name(&self, req : #req) -> ::workflow_rpc::asynchronous::client::result::Result<#resp> {
+                self.rpc.call::<#req, #resp>(#ops::#variant, req).await
+            }
+        }
+    });
+
+    let dispatch = methods.iter().map(|Method { name, variant, req, .. }| {
+        quote! {
+            #ops::#variant => {
+                let req = <#req as ::workflow_rpc::__private::borsh::BorshDeserialize>::try_from_slice(data)
+                    .map_err(|_| ::workflow_rpc::asynchronous::error::RpcResponseError::ReqDeserialize)?;
+                let resp = self.service.#name(ctx, req).await?;
+                ::workflow_rpc::__private::borsh::BorshSerialize::try_to_vec(&resp)
+                    .map_err(|_| ::workflow_rpc::asynchronous::error::RpcResponseError::RespSerialize)
+            },
+        }
+    });
+
+    Ok(quote! {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        #[repr(u32)]
+        #vis enum #ops {
+            #(#variants)*
+        }
+
+        impl ::std::convert::TryFrom<u32> for #ops {
+            type Error = ::workflow_rpc::asynchronous::ops::UnknownOpError;
+
+            fn try_from(op : u32) -> ::std::result::Result<Self, Self::Error> {
+                match op {
+                    #(#conversions)*
+                    _ => Err(::workflow_rpc::asynchronous::ops::UnknownOpError(op)),
+                }
+            }
+        }
+
+        impl From<#ops> for u32 {
+            fn from(op : #ops) -> u32 {
+                op as u32
+            }
+        }
+
+        #[cfg(not(any(target_arch = "wasm32", target_os = "solana")))]
+        #[::workflow_rpc::__private::async_trait]
+        #(#attrs)*
+        #vis trait #name : Send + Sync + 'static {
+            #(#server_methods)*
+        }
+
+        #[derive(Clone)]
+        #vis struct #client {
+            rpc : ::workflow_rpc::asynchronous::client::RpcClient<#ops>,
+        }
+
+        impl #client {
+            pub fn new(url : &str) -> ::workflow_rpc::asynchronous::client::result::Result<Self> {
+                Ok(Self::from_client(::workflow_rpc::asynchronous::client::RpcClient::new(url)?))
+            }
+
+            pub fn from_client(rpc : ::workflow_rpc::asynchronous::client::RpcClient<#ops>) -> Self {
+                Self { rpc }
+            }
+
+            pub fn rpc(&self) -> &::workflow_rpc::asynchronous::client::RpcClient<#ops> {
+                &self.rpc
+            }
+
+            #(#client_methods)*
+        }
+
+        #[cfg(not(any(target_arch = "wasm32", target_os = "solana")))]
+        #vis struct #handler<T>
+        where
+            T : #name
+        {
+            service : ::std::sync::Arc<T>,
+        }
+
+        #[cfg(not(any(target_arch = "wasm32", target_os = "solana")))]
+        impl<T> #handler<T>
+        where
+            T : #name
+        {
+            pub fn new(service : ::std::sync::Arc<T>) -> Self {
+                Self { service }
+            }
+        }
+
+        #[cfg(not(any(target_arch = "wasm32", target_os = "solana")))]
+        #[::workflow_rpc::__private::async_trait]
+        impl<T> ::workflow_rpc::asynchronous::server::RpcHandler<#ops> for #handler<T>
+        where
+            T : #name
+        {
+            async fn handle_request(
+                self : ::std::sync::Arc<Self>,
+                ctx : ::std::sync::Arc<::workflow_rpc::asynchronous::server::RpcContext>,
+                op : #ops,
+                data : &[u8]
+            ) -> ::workflow_rpc::asynchronous::result::RpcResult {
+                match op {
+                    #(#dispatch)*
+                }
+            }
+        }
+    })
+}
+
+fn parse_method(item: &TraitItem) -> syn::Result<Method> {
+    let method = match item {
+        TraitItem::Method(method) => method,
+        other => {
+            return Err(Error::new_spanned(other, "#[rpc] traits may only contain methods"));
+        }
+    };
+
+    let sig = &method.sig;
+    if RESERVED_METHODS.iter().any(|name| sig.ident == name) {
+        return Err(Error::new_spanned(&sig.ident, format!("#[rpc] method `{}` collides with a method generated on the client", sig.ident)));
+    }
+    if sig.asyncness.is_none() {
+        return Err(Error::new_spanned(sig, "#[rpc] methods must be async"));
+    }
+    if method.default.is_some() {
+        return Err(Error::new_spanned(method, "#[rpc] methods can not have a default implementation"));
+    }
+
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() && receiver.mutability.is_none() => { },
+        _ => {
+            return Err(Error::new_spanned(sig, "#[rpc] methods must take `&self`"));
+        }
+    }
+
+    let req = match (inputs.next(), inputs.next()) {
+        (Some(FnArg::Typed(arg)), None) => (*arg.ty).clone(),
+        _ => {
+            return Err(Error::new_spanned(&sig.inputs, "#[rpc] methods must take a single request argument"));
+        }
+    };
+
+    let resp = match &sig.output {
+        ReturnType::Type(_, ty) => result_ok_type(ty)?,
+        ReturnType::Default => {
+            return Err(Error::new_spanned(sig, "#[rpc] methods must return `Result<Resp, RpcResponseError>`"));
+        }
+    };
+
+    Ok(Method {
+        attrs : method.attrs.clone(),
+        name : sig.ident.clone(),
+        variant : format_ident!("{}", to_pascal_case(&sig.ident.to_string())),
+        req,
+        resp,
+    })
+}
+
+fn result_ok_type(ty: &Type) -> syn::Result<Type> {
+    if let Type::Path(path) = ty {
+        if let Some(segment) = path.path.segments.last() {
+            if segment.ident == "Result" {
+                if let PathArguments::AngleBracketed(args) = &segment.arguments {
+                    let mut args = args.args.iter();
+                    if let (Some(GenericArgument::Type(ok)), Some(GenericArgument::Type(err)), None) = (args.next(), args.next(), args.next()) {
+                        if is_response_error(err) {
+                            return Ok(ok.clone());
+                        }
+                        return Err(Error::new_spanned(err, "#[rpc] methods must use `RpcResponseError` as the error type"));
+                    }
+                }
+            }
+        }
+    }
+    Err(Error::new_spanned(ty, "#[rpc] methods must return `Result<Resp, RpcResponseError>`"))
+}
+
+fn is_response_error(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path.qself.is_none() && path.path.segments.last()
+            .map(|segment| segment.ident == "RpcResponseError" && segment.arguments.is_empty())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn to_pascal_case(name: &str) -> String {
+    name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_err(attr: TokenStream2, item: TokenStream2) -> String {
+        rpc_impl(attr, item).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn expands_ops_client_and_handler() {
+        let tokens = rpc_impl(quote! {}, quote! {
+            pub trait Service {
+                async fn get_value(&self, req : u32) -> Result<String, RpcResponseError>;
+                async fn set_value(&self, req : String) -> Result<(), RpcResponseError>;
+            }
+        }).unwrap().to_string();
+
+        assert!(tokens.contains("enum ServiceOps"));
+        assert!(tokens.contains("GetValue = 0"));
+        assert!(tokens.contains("SetValue = 1"));
+        assert!(tokens.contains("struct ServiceClient"));
+        assert!(tokens.contains("struct ServiceHandler"));
+        assert!(tokens.contains("UnknownOpError"));
+        assert!(!tokens.contains("workflow_core"));
+    }
+
+    #[test]
+    fn rejects_arguments() {
+        let err = expand_err(quote! { ops = 1 }, quote! {
+            trait Service {
+                async fn get(&self, req : u32) -> Result<u32, RpcResponseError>;
+            }
+        });
+        assert!(err.contains("does not take arguments"));
+    }
+
+    #[test]
+    fn rejects_foreign_error_type() {
+        let err = expand_err(quote! {}, quote! {
+            trait Service {
+                async fn get(&self, req : u32) -> Result<u32, std::io::Error>;
+            }
+        });
+        assert!(err.contains("`RpcResponseError` as the error type"));
+    }
+
+    #[test]
+    fn rejects_missing_error_type() {
+        let err = expand_err(quote! {}, quote! {
+            trait Service {
+                async fn get(&self, req : u32) -> Result<u32>;
+            }
+        });
+        assert!(err.contains("must return"));
+    }
+
+    #[test]
+    fn rejects_reserved_method_names() {
+        for name in RESERVED_METHODS {
+            let name = format_ident!("{}", name);
+            let err = expand_err(quote! {}, quote! {
+                trait Service {
+                    async fn #name(&self, req : u32) -> Result<u32, RpcResponseError>;
+                }
+            });
+            assert!(err.contains("collides with a method generated on the client"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn rejects_non_async_methods() {
+        let err = expand_err(quote! {}, quote! {
+            trait Service {
+                fn get(&self, req : u32) -> Result<u32, RpcResponseError>;
+            }
+        });
+        assert!(err.contains("must be async"));
+    }
+
+    #[test]
+    fn rejects_extra_arguments() {
+        let err = expand_err(quote! {}, quote! {
+            trait Service {
+                async fn get(&self, a : u32, b : u32) -> Result<u32, RpcResponseError>;
+            }
+        });
+        assert!(err.contains("single request argument"));
+    }
+
+    #[test]
+    fn rejects_empty_traits() {
+        let err = expand_err(quote! {}, quote! {
+            trait Service { }
+        });
+        assert!(err.contains("at least one method"));
+    }
+}
@@ -0,0 +1,173 @@
+use std::sync::Arc;
+use borsh::{BorshSerialize,BorshDeserialize};
+use serde::{Serialize,Deserialize};
+use super::error::RpcResponseError;
+
+/// Identity established by the server during the handshake
+/// and attached to the connection context.
+#[derive(Debug, Clone, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+pub struct RpcIdentity {
+    pub subject : String,
+    pub roles : Vec<String>,
+}
+
+impl RpcIdentity {
+    pub fn new(subject : &str, roles : &[&str]) -> Self {
+        RpcIdentity {
+            subject : subject.to_string(),
+            roles : roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    pub fn has_role(&self, role : &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+/// Handshake frame sent by the client under [`CtlOp::Handshake`](super::message::CtlOp).
+/// JSON-RPC clients send it as the params of [`JSONRPC_HANDSHAKE_METHOD`](super::jsonrpc::JSONRPC_HANDSHAKE_METHOD),
+/// e.g. `{"Token":"..."}`, `"Challenge"` or `{"Response":[...]}`.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+pub enum HandshakeRequest {
+    /// Authenticate using a bearer token
+    Token(String),
+    /// Request a challenge for challenge-response authentication
+    Challenge,
+    /// Response to the previously issued challenge
+    Response(Vec<u8>),
+}
+
+/// Handshake frame returned by the server on success.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+pub enum HandshakeResponse {
+    Challenge(Vec<u8>),
+    Accepted(RpcIdentity),
+}
+
+pub type ChallengeResponseFn = Arc<Box<(dyn Fn(&[u8]) -> Vec<u8> + Sync + Send)>>;
+
+/// Credentials used by the client to perform the handshake
+/// automatically each time the connection is opened.
+#[derive(Clone)]
+pub enum RpcCredentials {
+    Token(String),
+    ChallengeResponse(ChallengeResponseFn),
+}
+
+/// Credentials presented by the client, as seen by the server authenticator.
+#[derive(Debug, Clone)]
+pub enum AuthCredentials {
+    Token(String),
+    ChallengeResponse {
+        challenge : Vec<u8>,
+        response : Vec<u8>,
+    },
+}
+
+/// Action requested by a handshake frame, shared by the asynchronous
+/// and the blocking servers, which perform it with their authenticator.
+#[derive(Debug)]
+pub(crate) enum HandshakeStep {
+    /// Accept the connection with an anonymous identity
+    Anonymous,
+    /// Issue a challenge for challenge-response authentication
+    Challenge,
+    /// Validate the credentials presented by the client
+    Authenticate(AuthCredentials),
+}
+
+impl HandshakeStep {
+    /// Resolve a handshake request. Without an authenticator, every
+    /// request is accepted anonymously. A challenge response consumes
+    /// the challenge issued to the connection, obtained from `take_challenge`.
+    pub(crate) fn resolve<F>(request : HandshakeRequest, authenticator : bool, take_challenge : F) -> Result<Self, RpcResponseError>
+    where
+        F : FnOnce() -> Option<Vec<u8>>
+    {
+        if !authenticator {
+            return Ok(HandshakeStep::Anonymous);
+        }
+
+        match request {
+            HandshakeRequest::Token(token) => Ok(HandshakeStep::Authenticate(AuthCredentials::Token(token))),
+            HandshakeRequest::Challenge => Ok(HandshakeStep::Challenge),
+            HandshakeRequest::Response(response) => {
+                match take_challenge() {
+                    Some(challenge) => Ok(HandshakeStep::Authenticate(AuthCredentials::ChallengeResponse { challenge, response })),
+                    None => Err(RpcResponseError::Text("no challenge has been issued".to_string())),
+                }
+            }
+        }
+    }
+
+    /// Resolve a binary handshake frame. Without an authenticator
+    /// the frame is not inspected.
+    pub(crate) fn decode<F>(data : &[u8], authenticator : bool, take_challenge : F) -> Result<Self, RpcResponseError>
+    where
+        F : FnOnce() -> Option<Vec<u8>>
+    {
+        match HandshakeRequest::try_from_slice(data) {
+            Ok(request) => Self::resolve(request, authenticator, take_challenge),
+            Err(_) if !authenticator => Ok(HandshakeStep::Anonymous),
+            Err(_) => Err(RpcResponseError::ReqDeserialize),
+        }
+    }
+}
+
+/// Check made before processing any request other than a handshake:
+/// with an authenticator installed, the connection must have completed
+/// the handshake.
+pub(crate) fn check_handshake(authenticator : bool, authenticated : bool) -> Result<(), RpcResponseError> {
+    if authenticator && !authenticated {
+        return Err(RpcResponseError::HandshakeRequired);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_handshake_params() {
+        assert!(matches!(serde_json::from_str::<HandshakeRequest>(r#"{"Token":"secret"}"#).unwrap(), HandshakeRequest::Token(token) if token == "secret"));
+        assert!(matches!(serde_json::from_str::<HandshakeRequest>(r#""Challenge""#).unwrap(), HandshakeRequest::Challenge));
+        assert!(matches!(serde_json::from_str::<HandshakeRequest>(r#"{"Response":[1,2]}"#).unwrap(), HandshakeRequest::Response(response) if response == vec![1, 2]));
+    }
+
+    #[test]
+    fn json_handshake_result() {
+        let response = HandshakeResponse::Accepted(RpcIdentity::new("alice", &["admin"]));
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            serde_json::json!({ "Accepted" : { "subject" : "alice", "roles" : ["admin"] } })
+        );
+    }
+
+    #[test]
+    fn handshake_steps() {
+        let step = HandshakeStep::resolve(HandshakeRequest::Token("secret".to_string()), true, || None).unwrap();
+        assert!(matches!(step, HandshakeStep::Authenticate(AuthCredentials::Token(token)) if token == "secret"));
+        assert!(matches!(HandshakeStep::resolve(HandshakeRequest::Challenge, true, || None).unwrap(), HandshakeStep::Challenge));
+        assert!(matches!(HandshakeStep::resolve(HandshakeRequest::Challenge, false, || None).unwrap(), HandshakeStep::Anonymous));
+
+        let step = HandshakeStep::resolve(HandshakeRequest::Response(vec![2]), true, || Some(vec![1])).unwrap();
+        assert!(matches!(step, HandshakeStep::Authenticate(AuthCredentials::ChallengeResponse { challenge, response }) if challenge == vec![1] && response == vec![2]));
+        assert!(HandshakeStep::resolve(HandshakeRequest::Response(vec![2]), true, || None).is_err());
+    }
+
+    #[test]
+    fn handshake_frames_are_inspected_only_with_an_authenticator() {
+        assert!(matches!(HandshakeStep::decode(&[0xff], false, || None).unwrap(), HandshakeStep::Anonymous));
+        assert!(matches!(HandshakeStep::decode(&[0xff], true, || None), Err(RpcResponseError::ReqDeserialize)));
+        let data = HandshakeRequest::Challenge.try_to_vec().unwrap();
+        assert!(matches!(HandshakeStep::decode(&data, true, || None).unwrap(), HandshakeStep::Challenge));
+    }
+
+    #[test]
+    fn requests_require_the_handshake_with_an_authenticator() {
+        assert!(check_handshake(false, false).is_ok());
+        assert!(check_handshake(true, true).is_ok());
+        assert!(matches!(check_handshake(true, false), Err(RpcResponseError::HandshakeRequired)));
+    }
+}
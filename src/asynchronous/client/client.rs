@@ -1,18 +1,18 @@
 use borsh::{BorshSerialize,BorshDeserialize};
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
 use std::{
     mem::size_of, 
-    sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}}, 
+    sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}}, 
     marker::Send
 };
 use futures::{
     future::FutureExt, // for `.fuse()`
+    stream::{Stream, StreamExt},
     pin_mut,
     select,
 };
 use workflow_websocket::client::{
     WebSocket,
-    Settings as WebSocketSettings,
     Message as WebSocketMessage,
     Error as WebSocketError,
 };
@@ -20,6 +20,10 @@ use workflow_websocket::client::{
 use super::*;
 use super::error::Error;
 use super::result::Result;
+use crate::asynchronous::jsonrpc::*;
+use super::stats::*;
+use super::events::RpcEvents;
+use super::state::RpcStateCell;
 // use crate::asynchronous::client::error::Error;
 // use crate::asynchronous::client::result::Result;
 // use crate::message::*;
@@ -33,18 +37,89 @@ pub use workflow_websocket::client::Ctl;
 
 const STATUS_SUCCESS: u32 = 0;
 const STATUS_ERROR: u32 = 1;
+const STATUS_NOTIFICATION: u32 = 2;
+const STATUS_UNAUTHORIZED: u32 = 3;
+const STATUS_MALFORMED_HEADER: u32 = 4;
+const STATUS_UNKNOWN_OP: u32 = 5;
+const STATUS_STREAM_ITEM: u32 = 6;
+const STATUS_STREAM_END: u32 = 7;
+const STATUS_UPLOAD_ACK: u32 = 8;
+const STATUS_PUBLISH: u32 = 9;
 
 const RPC_CTL_RECEIVER_SHUTDOWN: u32 = 0;
+/// Interval at which `shutdown()` checks for drained calls
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 // pub type RpcResponseFn = Arc<Box<(dyn Fn(Result<Option<&[u8]>>) + Sync + Send)>>;
 pub type RpcResponseFn = Arc<Box<(dyn Fn(Result<&[u8]>) + Sync + Send)>>;
+pub type RpcNotificationFn = Arc<Box<(dyn Fn(&[u8]) + Sync + Send)>>;
 
+/// Map a non-success response status to the corresponding error.
+fn status_to_error(status : u32, data : &[u8]) -> Error {
+    match status {
+        STATUS_ERROR => {
+            match RpcResponseError::try_from_slice(data) {
+                Ok(err) => Error::RpcCall(err),
+                Err(_) => Error::ErrorDeserializingResponseData,
+            }
+        },
+        STATUS_UNAUTHORIZED => {
+            match RpcResponseError::try_from_slice(data) {
+                Ok(err) => Error::Unauthorized(err),
+                Err(_) => Error::ErrorDeserializingResponseData,
+            }
+        },
+        STATUS_MALFORMED_HEADER => Error::MalformedHeader,
+        STATUS_UNKNOWN_OP => Error::UnknownOp,
+        code => Error::StatusCode(code),
+    }
+}
 
 
 
+
+/// Request frame retained to be re-sent after a reconnect.
+enum PendingFrame {
+    Binary(Vec<u8>),
+    Text(String),
+}
+
+impl PendingFrame {
+    fn to_ws_msg(&self) -> WebSocketMessage {
+        match self {
+            PendingFrame::Binary(data) => data.clone().into(),
+            PendingFrame::Text(text) => WebSocketMessage::Text(text.clone()),
+        }
+    }
+
+    fn into_ws_msg(self) -> WebSocketMessage {
+        match self {
+            PendingFrame::Binary(data) => data.into(),
+            PendingFrame::Text(text) => WebSocketMessage::Text(text),
+        }
+    }
+
+    /// Size of the request data carried by the frame, as recorded in stats
+    fn payload_len(&self) -> usize {
+        match self {
+            PendingFrame::Binary(data) => data.len().saturating_sub(size_of::<ReqHeader>()),
+            PendingFrame::Text(text) => text.len(),
+        }
+    }
+}
+
 struct Pending {
     timestamp : Instant,
     callback : RpcResponseFn,
+    /// Op of a binary call; `None` for JSON-RPC calls, which are not tracked in stats
+    op : Option<u32>,
+    /// Overrides the client timeout for this call
+    timeout : Option<Duration>,
+    /// Present if the call is held across a disconnect or
+    /// buffered until the connection is re-established
+    frame : Option<PendingFrame>,
+    /// Connection the request was last posted on; `None` while buffered
+    generation : Option<u64>,
 }
 
 impl Pending {
@@ -52,36 +127,193 @@ impl Pending {
         Self {
             timestamp: Instant::now(),
             callback,
+            op : None,
+            timeout : None,
+            frame : None,
+            generation : None,
+        }
+    }
+
+    fn with_frame(self, frame : Option<PendingFrame>) -> Self {
+        Self {
+            frame,
+            ..self
+        }
+    }
+
+    fn with_generation(self, generation : u64) -> Self {
+        Self {
+            generation : Some(generation),
+            ..self
+        }
+    }
+
+    fn with_timeout(self, timeout : Option<Duration>) -> Self {
+        Self {
+            timeout,
+            ..self
+        }
+    }
+
+    fn with_op(op : u32, callback: RpcResponseFn) -> Self {
+        Self {
+            op : Some(op),
+            ..Self::new(callback)
+        }
+    }
+}
+
+/// Frame re-sent by [`restore()`](Inner::restore).
+struct Replay {
+    msg : WebSocketMessage,
+    /// Op and request size of a buffered call sent for the first time,
+    /// counted in stats once the frame has been written
+    first : Option<(u32, usize)>,
+}
+
+/// Frames of the calls to be sent once the connection `generation` has
+/// been restored: calls posted on an earlier connection and buffered
+/// calls. Replayed calls are marked as posted on `generation`; those that
+/// are not to be held across disconnects give up their frame.
+fn replay_frames(pending : &mut AHashMap<u64, Pending>, generation : u64, holds : impl Fn(Option<u32>) -> bool) -> Vec<Replay> {
+    pending
+        .values_mut()
+        .filter_map(|pending| {
+            // posted on the current connection; replaying it
+            // would execute the call twice
+            if pending.generation == Some(generation) {
+                return None;
+            }
+            let frame = pending.frame.as_ref()?;
+            let first = match pending.generation {
+                None => pending.op.map(|op| (op, frame.payload_len())),
+                Some(_) => None,
+            };
+            let msg = frame.to_ws_msg();
+            if !holds(pending.op) {
+                pending.frame = None;
+            }
+            pending.generation = Some(generation);
+            Some(Replay { msg, first })
+        })
+        .collect()
+}
+
+/// Cancels a pending call when the future awaiting it is dropped.
+/// Has no effect once the call has completed.
+struct CancelOnDrop {
+    inner : Arc<Inner>,
+    id : u64,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.inner.cancel(self.id);
+    }
+}
+
+/// Local subscribers of a topic.
+#[derive(Default)]
+struct TopicSubscribers {
+    subscribers : AHashMap<u64, Sender<Vec<u8>>>,
+    /// Set while the subscribe request of the first subscriber is in
+    /// flight; later subscribers wait here for its outcome
+    waiting : Option<Vec<(u64, Sender<std::result::Result<(), String>>)>>,
+}
+
+/// Removes a subscriber whose `subscribe()` call was dropped before
+/// completing. An abandoned subscribe request fails its waiters.
+struct SubscribeOnDrop<'a> {
+    inner : &'a Arc<Inner>,
+    topic : &'a str,
+    id : u64,
+    first : bool,
+}
+
+impl Drop for SubscribeOnDrop<'_> {
+    fn drop(&mut self) {
+        if self.first {
+            self.inner.complete_subscribe(self.topic, self.id, Err("subscribe request abandoned".to_string()));
+        } else {
+            self.inner.unsubscribe(self.topic, self.id);
         }
     }
 }
 
 pub struct Inner {
     ws : WebSocket,
-    is_open : AtomicBool,
+    state : Arc<RpcStateCell>,
     pending : Arc<Mutex<AHashMap<u64, Pending>>>,
+    notifications : Mutex<AHashMap<u32, RpcNotificationFn>>,
     receiver_is_running : AtomicBool,
     timeout_is_running : AtomicBool,
     receiver_shutdown : SingleTrigger,
     timeout_shutdown : ReqRespTrigger,
     timeout_timer_interval : AtomicU64,
     timeout_duration : AtomicU64,
-    ctl_channel : Mutex<Option<(Sender<Ctl>, Receiver<Ctl>)>>,
+    credentials : Mutex<Option<RpcCredentials>>,
+    identity : Mutex<Option<RpcIdentity>>,
+    /// Callers of `connect()` waiting for the handshake of the connection
+    /// they opened, with the generation of that connection
+    handshake_waiters : Mutex<Vec<(u64, Sender<std::result::Result<(), String>>)>>,
+    /// Set when the connection is closed because its handshake failed
+    handshake_failed : AtomicBool,
+    stats : RpcStats,
+    streams : Mutex<AHashMap<u64, Sender<Result<Vec<u8>>>>>,
+    uploads : Mutex<AHashMap<u64, Sender<u32>>>,
+    subscriptions : Mutex<AHashMap<String, TopicSubscribers>>,
+    subscription_capacity : usize,
+    pub(super) stream_window : AtomicU32,
+    max_in_flight : Option<usize>,
+    reconnect : ReconnectPolicy,
+    on_disconnect : DisconnectPolicy,
+    outage : OutagePolicy,
+    idempotent : Mutex<AHashSet<u32>>,
+    reconnecting : AtomicBool,
+    /// Set once the current connection has completed the handshake
+    /// and been restored; calls are buffered until then
+    ready : AtomicBool,
+    /// Incremented each time a connection is lost, identifying the
+    /// connection requests are posted on
+    generation : AtomicU64,
+    /// Closed once the running reconnection task exits
+    reconnect_stopped : Mutex<Option<Receiver<()>>>,
+    events : RpcEvents,
 }
 
 impl Inner {
-    fn new(url : &str) -> Result<Self> {
+    fn new(url : &str, options : RpcClientOptions) -> Result<Self> {
         let inner = Inner {
-            ws: WebSocket::new(url, WebSocketSettings::default())?,
+            ws: WebSocket::new(url, options.ws_settings)?,
             pending: Arc::new(Mutex::new(AHashMap::new())),
-            is_open : AtomicBool::new(false),
+            notifications : Mutex::new(AHashMap::new()),
+            state : Arc::new(RpcStateCell::new(RpcClientState::Closed)),
             receiver_is_running : AtomicBool::new(false),
             receiver_shutdown : SingleTrigger::new(),
             timeout_is_running : AtomicBool::new(false),
             timeout_shutdown : ReqRespTrigger::new(),
-            timeout_duration : AtomicU64::new(60_000),
-            timeout_timer_interval : AtomicU64::new(5_000),
-            ctl_channel : Mutex::new(None),
+            timeout_duration : AtomicU64::new(options.timeout.as_millis() as u64),
+            timeout_timer_interval : AtomicU64::new(options.sweep_interval.as_millis() as u64),
+            credentials : Mutex::new(None),
+            identity : Mutex::new(None),
+            handshake_waiters : Mutex::new(Vec::new()),
+            handshake_failed : AtomicBool::new(false),
+            stats : RpcStats::default(),
+            streams : Mutex::new(AHashMap::new()),
+            uploads : Mutex::new(AHashMap::new()),
+            subscriptions : Mutex::new(AHashMap::new()),
+            subscription_capacity : options.subscription_capacity,
+            stream_window : AtomicU32::new(options.stream_window),
+            max_in_flight : options.max_in_flight,
+            reconnect : options.reconnect,
+            on_disconnect : options.on_disconnect,
+            outage : options.outage,
+            idempotent : Mutex::new(options.idempotent.into_iter().collect()),
+            reconnecting : AtomicBool::new(false),
+            generation : AtomicU64::new(0),
+            ready : AtomicBool::new(false),
+            reconnect_stopped : Mutex::new(None),
+            events : RpcEvents::default(),
         };
 
         Ok(inner)
@@ -107,13 +339,18 @@ impl Inner {
                         let mut purge = Vec::<u64>::new();
                         let timeout = Duration::from_millis(self.timeout_duration.load(Ordering::Relaxed));
                         for (id,pending) in pending.iter() {
-                            if pending.timestamp.elapsed() > timeout {
+                            if pending.timestamp.elapsed() > pending.timeout.unwrap_or(timeout) {
                                 purge.push(*id);
+                                if let Some(op) = pending.op {
+                                    self.stats.timeout(op);
+                                }
                                 (pending.callback)(Err(Error::Timeout));
                             }
                         }
+                        let mut uploads = self.uploads.lock().unwrap();
                         for id in purge.iter() {
                             pending.remove(id);
+                            uploads.remove(id);
                         }
                     },
                 }
@@ -137,31 +374,69 @@ impl Inner {
                     WebSocketMessage::Binary(data) => {
                         self.handle_binary_response(&data);
                     },
-                    WebSocketMessage::Text(_text) => {
-                        // self.handle_json_response(text);
+                    WebSocketMessage::Text(text) => {
+                        self.handle_json_response(&text);
                     },
                     WebSocketMessage::Ctl(ctl) => {
                         match ctl {
                             Ctl::Open => {
-                                self.is_open.store(true,Ordering::SeqCst);
+                                // an opening connection is abandoned during shutdown
+                                let opened = self.state.transition(RpcClientState::Connecting, RpcClientState::Open)
+                                    || self.state.transition(RpcClientState::Reconnecting, RpcClientState::Open);
+                                if !opened {
+                                    log_trace!("RPC connection opened while {:?}; ignored", self.state.get());
+                                    continue;
+                                }
+                                self.events.emit(RpcClientEvent::Connected);
+                                let this = self.clone();
+                                workflow_core::task::spawn(async move {
+                                    this.open().await;
+                                });
                             },
                             Ctl::Closed => {
-                                self.is_open.store(false,Ordering::SeqCst);
+                                // reset here rather than on open, as `connect()`
+                                // may restore the next connection before its
+                                // open event has been processed
+                                self.ready.store(false, Ordering::SeqCst);
+                                self.generation.fetch_add(1, Ordering::SeqCst);
+                                self.identity.lock().unwrap().take();
+                                self.events.emit(RpcClientEvent::Disconnected);
+                                match &self.reconnect {
+                                    // the client is shutting down
+                                    _ if matches!(self.state.get(), RpcClientState::Closing | RpcClientState::ShutDown) => {
+                                        self.handle_disconnect(true);
+                                    },
+                                    // reconnecting would present the rejected credentials again
+                                    _ if self.handshake_failed.swap(false, Ordering::SeqCst) => {
+                                        self.state.set(RpcClientState::Closed);
+                                        self.handle_disconnect(true);
+                                    },
+                                    ReconnectPolicy::Default => {
+                                        self.state.set(RpcClientState::Reconnecting);
+                                        self.handle_disconnect(false);
+                                    },
+                                    ReconnectPolicy::Disabled => {
+                                        self.state.set(RpcClientState::Closed);
+                                        self.handle_disconnect(true);
+                                        let this = self.clone();
+                                        workflow_core::task::spawn(async move {
+                                            if let Err(err) = this.ws.disconnect().await {
+                                                log_trace!("RPC unable to disconnect: {}", err);
+                                            }
+                                        });
+                                    },
+                                    ReconnectPolicy::Backoff(backoff) => {
+                                        self.state.set(RpcClientState::Reconnecting);
+                                        self.handle_disconnect(false);
+                                        self.clone().reconnect_task(backoff.clone());
+                                    }
+                                }
                             },
                             Ctl::RpcCtl(RPC_CTL_RECEIVER_SHUTDOWN) => {
                                 break;
                             },
                             _ => { }
                         }
-
-                        let sender = match self.ctl_channel.lock().unwrap().as_ref() {
-                            Some(channel) => Some(channel.0.clone()),
-                            None => None
-                        };
-
-                        if let Some(sender) = sender {
-                            sender.clone().send(ctl).await.unwrap();
-                        }
                     }
                 }
             }
@@ -172,6 +447,576 @@ impl Inner {
     }
 
 
+    /// Deliver a response frame to an open stream. Returns `false`
+    /// if the frame does not belong to a stream.
+    fn handle_stream_response(&self, msg : &RespMessage<'_>) -> bool {
+        let mut streams = self.streams.lock().unwrap();
+        let sender = match streams.get(&msg.id) {
+            Some(sender) => sender,
+            None => { return false; }
+        };
+
+        match msg.status {
+            STATUS_STREAM_ITEM => {
+                if sender.try_send(Ok(msg.data.to_vec())).is_err() {
+                    log_error!("RPC stream {} item exceeds the stream window", msg.id);
+                }
+            },
+            STATUS_STREAM_END => {
+                streams.remove(&msg.id);
+            },
+            status => {
+                let _ = sender.try_send(Err(status_to_error(status, msg.data)));
+                streams.remove(&msg.id);
+            }
+        }
+
+        true
+    }
+
+    /// Open a response stream for `op`. Items are delivered through
+    /// the returned receiver, which is closed once the stream ends.
+    pub(super) async fn open_stream(
+        &self,
+        op : u32,
+        message : Message<'_>,
+    ) -> Result<(u64, u32, Receiver<Result<Vec<u8>>>)> {
+        if self.is_shutting_down() {
+            return Err(Error::Shutdown);
+        }
+        if !self.ready.load(Ordering::SeqCst) {
+            return Err(WebSocketError::NotConnected.into());
+        }
+
+        let window = self.stream_window.load(Ordering::SeqCst).max(1);
+        let request = StreamRequest { op, window, data : message.data().to_vec() };
+        let data = request.try_to_vec().map_err(|_| { Error::BorshSerialize })?;
+
+        let id = u64::from_le_bytes(rand::random::<[u8; 8]>());
+        // one extra slot for the error frame that may terminate a full window
+        let (sender, receiver) = bounded(window as usize + 1);
+        self.streams.lock().unwrap().insert(id, sender);
+
+        if let Err(err) = self.ws.post(to_ws_msg((ReqHeader{ op : CtlOp::Stream as u32, id }, Message::Request(&data)))).await {
+            self.streams.lock().unwrap().remove(&id);
+            return Err(err.into());
+        }
+
+        Ok((id, window, receiver))
+    }
+
+    /// Grant the server `credits` more items for the stream `id`.
+    pub(super) fn stream_ack(self : &Arc<Self>, id : u64, credits : u32) {
+        if !self.streams.lock().unwrap().contains_key(&id) {
+            return;
+        }
+
+        let data = match credits.try_to_vec() {
+            Ok(data) => data,
+            Err(_) => { return; }
+        };
+        let this = self.clone();
+        workflow_core::task::spawn(async move {
+            let msg = to_ws_msg((ReqHeader{ op : CtlOp::StreamAck as u32, id }, Message::Post(&data)));
+            if let Err(err) = this.ws.post(msg).await {
+                log_trace!("RPC unable to post stream ack for {}: {}", id, err);
+            }
+        });
+    }
+
+    fn handle_upload_ack(&self, id : u64, data : &[u8]) {
+        let credits = match u32::try_from_slice(data) {
+            Ok(credits) => credits,
+            Err(err) => {
+                log_error!("RPC malformed upload ack: {}", err);
+                return;
+            }
+        };
+
+        if let Some(sender) = self.uploads.lock().unwrap().get(&id) {
+            let _ = sender.try_send(credits);
+        }
+    }
+
+    /// Upload `chunks` to the handler of `op`, sending each chunk as the
+    /// server grants credits for it. The call timeout is measured from
+    /// the last chunk sent, so long uploads are not cut short.
+    pub(super) async fn upload_with_buffer<S>(
+        self : &Arc<Self>,
+        op : u32,
+        message : Message<'_>,
+        mut chunks : S,
+    ) -> Result<Vec<u8>>
+    where
+        S : Stream<Item = Vec<u8>> + Unpin
+    {
+        if self.is_shutting_down() {
+            return Err(Error::Shutdown);
+        }
+        if !self.ready.load(Ordering::SeqCst) {
+            return Err(WebSocketError::NotConnected.into());
+        }
+
+        let request = UploadRequest { op, data : message.data().to_vec() };
+        let data = request.try_to_vec().map_err(|_| { Error::BorshSerialize })?;
+
+        let id = u64::from_le_bytes(rand::random::<[u8; 8]>());
+        let (sender,receiver) = oneshot();
+        let (credit_sender, credit_receiver) = unbounded();
+
+        self.insert_pending(id, Pending::with_op(op, Arc::new(Box::new(move |result| {
+            let resp = match result {
+                Ok(data) => Ok(data.to_vec()),
+                Err(e) => Err(e),
+            };
+            let _ = sender.try_send(resp);
+        }))))?;
+        self.uploads.lock().unwrap().insert(id, credit_sender);
+
+        let _cancel = CancelOnDrop { inner : self.clone(), id };
+        self.ws.post(to_ws_msg((ReqHeader{ op : CtlOp::Upload as u32, id }, Message::Request(&data)))).await?;
+        self.stats.sent(op, request.data.len());
+
+        let mut credits = 0;
+        while let Some(chunk) = chunks.next().await {
+            while credits == 0 {
+                let granted = credit_receiver.recv().fuse();
+                let response = receiver.recv().fuse();
+                pin_mut!(granted, response);
+                select! {
+                    granted = granted => match granted {
+                        Ok(granted) => { credits += granted; },
+                        // the server has responded before the upload completed
+                        Err(_) => { return receiver.recv().await?; }
+                    },
+                    // the call has failed or timed out while waiting for credits
+                    response = response => { return response?; }
+                }
+            }
+            credits -= 1;
+
+            if let Some(pending) = self.pending.lock().unwrap().get_mut(&id) {
+                pending.timestamp = Instant::now();
+            }
+            self.ws.post(to_ws_msg((ReqHeader{ op : CtlOp::UploadChunk as u32, id }, Message::Post(&chunk)))).await?;
+            self.stats.sent_bytes(op, chunk.len());
+        }
+
+        self.ws.post(to_ws_msg((ReqHeader{ op : CtlOp::UploadEnd as u32, id }, Message::Post(&[])))).await?;
+        receiver.recv().await?
+    }
+
+    fn handle_publication(&self, data : &[u8]) {
+        let publication = match Publication::try_from_slice(data) {
+            Ok(publication) => publication,
+            Err(err) => {
+                log_error!("RPC malformed publication: {}", err);
+                return;
+            }
+        };
+
+        match self.subscriptions.lock().unwrap().get(&publication.topic) {
+            Some(entry) => {
+                for sender in entry.subscribers.values() {
+                    if sender.try_send(publication.data.clone()).is_err() {
+                        log_trace!("rpc subscriber of {} is lagging; publication dropped", publication.topic);
+                    }
+                }
+            },
+            None => {
+                log_trace!("rpc subscription for topic {} not found", publication.topic);
+            }
+        }
+    }
+
+    async fn post_subscription(self : &Arc<Self>, op : CtlOp, topic : &str) -> Result<()> {
+        let data = topic.to_string().try_to_vec().map_err(|_| { Error::BorshSerialize })?;
+        self.call_with_buffer(op as u32, Message::Request(&data), None).await?;
+        Ok(())
+    }
+
+    /// Register a local subscriber for `topic`, subscribing the
+    /// connection to the topic if this is its first subscriber. While
+    /// the subscribe request of the first subscriber is in flight,
+    /// later subscribers wait for its outcome and fail with it.
+    pub(super) async fn subscribe(self : &Arc<Self>, topic : &str) -> Result<(u64, Receiver<Vec<u8>>)> {
+        let id = u64::from_le_bytes(rand::random::<[u8; 8]>());
+        let (sender, receiver) = bounded(self.subscription_capacity.max(1));
+        let (first, outcome) = {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            let entry = subscriptions.entry(topic.to_string()).or_default();
+            entry.subscribers.insert(id, sender);
+            if entry.subscribers.len() == 1 {
+                entry.waiting = Some(Vec::new());
+                (true, None)
+            } else if let Some(waiting) = &mut entry.waiting {
+                let (sender, receiver) = oneshot();
+                waiting.push((id, sender));
+                (false, Some(receiver))
+            } else {
+                (false, None)
+            }
+        };
+
+        let guard = SubscribeOnDrop { inner : self, topic, id, first };
+        if first {
+            let result = self.post_subscription(CtlOp::Subscribe, topic).await;
+            self.complete_subscribe(topic, id, result.as_ref().map(|_| ()).map_err(|err| err.to_string()));
+            std::mem::forget(guard);
+            result?;
+        } else if let Some(outcome) = outcome {
+            let outcome = outcome.recv().await;
+            std::mem::forget(guard);
+            outcome?.map_err(Error::SubscriptionFailed)?;
+        } else {
+            std::mem::forget(guard);
+        }
+
+        Ok((id, receiver))
+    }
+
+    /// Deliver the outcome of the subscribe request for `topic` issued
+    /// by subscriber `id` to the subscribers waiting for it. On failure
+    /// all of them are removed.
+    fn complete_subscribe(&self, topic : &str, id : u64, result : std::result::Result<(), String>) {
+        let waiting = {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            let entry = match subscriptions.get_mut(topic) {
+                Some(entry) => entry,
+                None => { return; }
+            };
+            let waiting = entry.waiting.take().unwrap_or_default();
+            if result.is_err() {
+                entry.subscribers.remove(&id);
+                for (id, _) in waiting.iter() {
+                    entry.subscribers.remove(id);
+                }
+                if entry.subscribers.is_empty() {
+                    subscriptions.remove(topic);
+                }
+            }
+            waiting
+        };
+
+        for (_, sender) in waiting {
+            let _ = sender.try_send(result.clone());
+        }
+    }
+
+    /// Remove a local subscriber, unsubscribing the connection
+    /// from `topic` once it has no subscribers left.
+    pub(super) fn unsubscribe(self : &Arc<Self>, topic : &str, id : u64) {
+        let last = {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            match subscriptions.get_mut(topic) {
+                Some(entry) => {
+                    entry.subscribers.remove(&id);
+                    if entry.subscribers.is_empty() {
+                        subscriptions.remove(topic);
+                        true
+                    } else {
+                        false
+                    }
+                },
+                None => false
+            }
+        };
+
+        if last && self.ws.is_open() {
+            let this = self.clone();
+            let topic = topic.to_string();
+            workflow_core::task::spawn(async move {
+                if let Err(err) = this.post_subscription(CtlOp::Unsubscribe, &topic).await {
+                    log_trace!("RPC unable to unsubscribe from {}: {}", topic, err);
+                }
+            });
+        }
+    }
+
+    pub(super) fn set_idempotent(&self, op : u32, idempotent : bool) {
+        let mut ops = self.idempotent.lock().unwrap();
+        if idempotent {
+            ops.insert(op);
+        } else {
+            ops.remove(&op);
+        }
+    }
+
+    /// Whether a sent call is held across a disconnect; `op` is `None`
+    /// for JSON-RPC calls.
+    fn holds(&self, op : Option<u32>) -> bool {
+        self.on_disconnect == DisconnectPolicy::Hold
+            || op.map(|op| self.idempotent.lock().unwrap().contains(&op)).unwrap_or(false)
+    }
+
+    /// Register a pending call and post its request frame. Until the
+    /// connection is open and restored after the handshake, calls are
+    /// buffered and sent by [`restore()`](Self::restore); while the
+    /// connection is down they are buffered or rejected according to
+    /// the outage policy. Control requests are tied to the connection,
+    /// are posted as soon as it is open and are never held or buffered.
+    async fn submit(&self, id : u64, pending : Pending, frame : PendingFrame) -> Result<()> {
+        if self.is_shutting_down() {
+            return Err(Error::Shutdown);
+        }
+
+        let is_ctl = pending.op.map(|op| CtlOp::try_from(op).is_ok()).unwrap_or(false);
+        if is_ctl {
+            if !self.ws.is_open() {
+                return Err(WebSocketError::NotConnected.into());
+            }
+            let generation = self.generation.load(Ordering::SeqCst);
+            self.insert_pending(id, pending.with_generation(generation))?;
+            return self.post_pending(id, frame.into_ws_msg()).await;
+        }
+
+        let sent = pending.op.map(|op| (op, frame.payload_len()));
+        let msg = {
+            // `restore()` marks the connection ready under the same lock,
+            // so a call buffered here is always picked up by it
+            let mut map = self.pending.lock().unwrap();
+            if let Some(max_in_flight) = self.max_in_flight {
+                if map.len() >= max_in_flight {
+                    return Err(Error::MaxInFlight);
+                }
+            }
+
+            if !self.ready.load(Ordering::SeqCst) {
+                if !self.ws.is_open() && self.outage == OutagePolicy::Reject {
+                    return Err(WebSocketError::NotConnected.into());
+                }
+                map.insert(id, pending.with_frame(Some(frame)));
+                return Ok(());
+            }
+
+            let (msg, held) = if self.holds(pending.op) {
+                (frame.to_ws_msg(), Some(frame))
+            } else {
+                (frame.into_ws_msg(), None)
+            };
+            let generation = self.generation.load(Ordering::SeqCst);
+            map.insert(id, pending.with_frame(held).with_generation(generation));
+            msg
+        };
+
+        self.post_pending(id, msg).await?;
+        // buffered calls are counted by `restore()` once it has sent them
+        if let Some((op, size)) = sent {
+            self.stats.sent(op, size);
+        }
+        Ok(())
+    }
+
+    async fn post_pending(&self, id : u64, msg : WebSocketMessage) -> Result<()> {
+        if let Err(err) = self.ws.post(msg).await {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Fail calls that can not survive the loss of the connection with
+    /// [`Error::Disconnected`]; with `all` set, held and buffered calls
+    /// are failed as well. Streams and uploads are always failed.
+    fn handle_disconnect(&self, all : bool) {
+        self.fail_calls(all, || Error::Disconnected);
+    }
+
+    fn fail_calls(&self, all : bool, error : impl Fn() -> Error) {
+        for (_, sender) in self.streams.lock().unwrap().drain() {
+            let _ = sender.try_send(Err(error()));
+        }
+        self.uploads.lock().unwrap().clear();
+
+        let failed : Vec<Pending> = {
+            let mut pending = self.pending.lock().unwrap();
+            let ids : Vec<u64> = pending.iter()
+                .filter(|(_, pending)| all || pending.frame.is_none())
+                .map(|(id, _)| *id)
+                .collect();
+            ids.iter().filter_map(|id| pending.remove(id)).collect()
+        };
+
+        for pending in failed {
+            (pending.callback)(Err(error()));
+        }
+    }
+
+    /// Re-open the connection after it has been lost, waiting between
+    /// attempts as configured by `backoff`. Pending calls are failed if
+    /// reconnection is abandoned.
+    fn reconnect_task(self : Arc<Self>, backoff : Backoff) {
+        if self.reconnecting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let (stopped, receiver) = oneshot();
+        *self.reconnect_stopped.lock().unwrap() = Some(receiver);
+        let watch = self.state.watch();
+        workflow_core::task::spawn(async move {
+            // stop the WebSocket from reconnecting on its own schedule
+            if let Err(err) = self.ws.disconnect().await {
+                log_trace!("RPC unable to disconnect: {}", err);
+            }
+
+            let mut attempt = 0;
+            loop {
+                if self.state.get() != RpcClientState::Reconnecting {
+                    break;
+                }
+
+                if backoff.max_attempts.map(|max| attempt >= max).unwrap_or(false) {
+                    self.state.transition(RpcClientState::Reconnecting, RpcClientState::Closed);
+                    self.events.emit(RpcClientEvent::ReconnectFailed { attempts : attempt });
+                    self.handle_disconnect(true);
+                    break;
+                }
+
+                let delay = backoff.delay(attempt);
+                attempt += 1;
+                self.events.emit(RpcClientEvent::Reconnecting { attempt, delay });
+                {
+                    let delay = async_std::task::sleep(delay).fuse();
+                    let left = watch.left(RpcClientState::Reconnecting).fuse();
+                    pin_mut!(delay, left);
+                    select! {
+                        () = delay => { },
+                        // the client has been shut down in the meantime
+                        _ = left => { break; },
+                    }
+                }
+
+                // a connection attempt can take long to fail; it is
+                // abandoned as soon as the client is shut down
+                let connect = self.ws.connect(true).fuse();
+                let left = watch.left(RpcClientState::Reconnecting).fuse();
+                pin_mut!(connect, left);
+                select! {
+                    result = connect => match result {
+                        Ok(_) => { break; },
+                        Err(err) => {
+                            log_trace!("RPC reconnect attempt {} failed: {}", attempt, err);
+                        }
+                    },
+                    _ = left => { break; },
+                }
+            }
+
+            self.reconnecting.store(false, Ordering::SeqCst);
+            let _ = stopped.try_send(());
+        });
+    }
+
+    /// Perform the handshake on the connection that has just opened and
+    /// restore it. If the handshake fails, the connection is closed.
+    /// Callers of `connect()` waiting for the connection are told the outcome.
+    async fn open(self : &Arc<Self>) {
+        let generation = self.generation.load(Ordering::SeqCst);
+        let result = match self.handshake().await {
+            Ok(_) => {
+                self.restore().await;
+                Ok(())
+            },
+            Err(err) => {
+                log_error!("RPC handshake failure: {}", err);
+                let error = err.to_string();
+                self.fail_handshake(&error);
+                if let Err(err) = self.ws.disconnect().await {
+                    log_trace!("RPC unable to disconnect: {}", err);
+                }
+                Err(error)
+            }
+        };
+        self.complete_handshake(generation, result);
+    }
+
+    /// Fail pending and buffered calls with [`Error::HandshakeFailed`]
+    /// and keep the closing connection from being re-opened.
+    fn fail_handshake(&self, error : &str) {
+        self.handshake_failed.store(true, Ordering::SeqCst);
+        self.fail_calls(true, || Error::HandshakeFailed(error.to_string()));
+        self.events.emit(RpcClientEvent::HandshakeFailed { error : error.to_string() });
+    }
+
+    /// Report the outcome of the handshake on connection `generation`
+    /// to the callers of `connect()` waiting for it.
+    fn complete_handshake(&self, generation : u64, result : std::result::Result<(), String>) {
+        let waiters : Vec<_> = {
+            let mut waiters = self.handshake_waiters.lock().unwrap();
+            let (completed, waiting) = waiters.drain(..).partition(|(waiting_for, _)| *waiting_for <= generation);
+            *waiters = waiting;
+            completed
+        };
+        for (_, sender) in waiters {
+            let _ = sender.try_send(result.clone());
+        }
+    }
+
+    /// Register a caller of `connect()` waiting for the outcome of the
+    /// handshake on the connection it is about to open.
+    fn wait_for_handshake(&self) -> Receiver<std::result::Result<(), String>> {
+        let (sender, receiver) = oneshot();
+        let generation = self.generation.load(Ordering::SeqCst);
+        let mut waiters = self.handshake_waiters.lock().unwrap();
+        // callers whose connection attempt failed are no longer waiting
+        waiters.retain(|(_, sender)| !sender.is_closed());
+        waiters.push((generation, sender));
+        receiver
+    }
+
+    /// Re-establish connection state once the connection has opened
+    /// and the handshake has completed: topic subscriptions are renewed,
+    /// and calls sent or buffered before the connection opened are sent.
+    /// Buffered calls that are not held once sent are failed by a
+    /// subsequent disconnect.
+    async fn restore(self : &Arc<Self>) {
+        self.resubscribe().await;
+
+        let generation = self.generation.load(Ordering::SeqCst);
+        let frames = {
+            let mut pending = self.pending.lock().unwrap();
+            let frames = replay_frames(&mut pending, generation, |op| self.holds(op));
+            // calls submitted from here on are posted directly
+            self.ready.store(true, Ordering::SeqCst);
+            frames
+        };
+
+        for Replay { msg, first } in frames {
+            match self.ws.post(msg).await {
+                Ok(_) => {
+                    if let Some((op, size)) = first {
+                        self.stats.sent(op, size);
+                    }
+                },
+                Err(err) => { log_error!("RPC unable to re-send call: {}", err); }
+            }
+        }
+    }
+
+    /// Subscribe the connection to all topics with local subscribers.
+    async fn resubscribe(self : &Arc<Self>) {
+        let topics : Vec<String> = self.subscriptions.lock().unwrap().keys().cloned().collect();
+        for topic in topics {
+            if let Err(err) = self.post_subscription(CtlOp::Subscribe, &topic).await {
+                log_error!("RPC unable to resubscribe to {}: {}", topic, err);
+            }
+        }
+    }
+
+    fn post_cancel(self : &Arc<Self>, id : u64) {
+        if !self.ws.is_open() {
+            return;
+        }
+
+        let this = self.clone();
+        workflow_core::task::spawn(async move {
+            let msg = to_ws_msg((ReqHeader{ op : CtlOp::Cancel as u32, id }, Message::Post(&[])));
+            if let Err(err) = this.ws.post(msg).await {
+                log_trace!("RPC unable to post cancellation for {}: {}", id, err);
+            }
+        });
+    }
+
     fn handle_binary_response(&self, response : &[u8]) {
 
         if response.len() < size_of::<RespHeader>() {
@@ -180,24 +1025,34 @@ impl Inner {
 
         let msg = RespMessage::try_from(response);
         match msg {
+            Ok(msg) if msg.status == STATUS_NOTIFICATION => {
+                self.handle_notification(msg.id as u32, msg.data);
+            },
+            Ok(msg) if msg.status == STATUS_PUBLISH => {
+                self.handle_publication(msg.data);
+            },
+            Ok(msg) if msg.status == STATUS_UPLOAD_ACK => {
+                self.handle_upload_ack(msg.id, msg.data);
+            },
+            Ok(msg) if self.handle_stream_response(&msg) => { },
             Ok(msg) => {
 
-                match self.pending.lock().unwrap().remove(&msg.id) {
+                let pending = self.pending.lock().unwrap().remove(&msg.id);
+                // a response ends the upload for the same id, if any
+                self.uploads.lock().unwrap().remove(&msg.id);
+                match pending {
                     Some(pending) => {
 
+                        if let Some(op) = pending.op {
+                            self.stats.received(op, pending.timestamp.elapsed(), msg.data.len(), msg.status == STATUS_SUCCESS);
+                        }
+
                         match msg.status {
                             STATUS_SUCCESS  => { 
                                 (pending.callback)(Ok(msg.data)); 
                             },
-                            STATUS_ERROR => {
-                                if let Ok(err) = RpcResponseError::try_from_slice(msg.data) {
-                                    (pending.callback)(Err(Error::RpcCall(err)));
-                                } else {
-                                    (pending.callback)(Err(Error::ErrorDeserializingResponseData));
-                                }
-                            }
-                            code  => { 
-                                (pending.callback)(Err(Error::StatusCode(code))) 
+                            status => {
+                                (pending.callback)(Err(status_to_error(status, msg.data)));
                             },
                         }
                     },
@@ -212,9 +1067,279 @@ impl Inner {
             }
         }
     }   
+
+    fn insert_pending(&self, id : u64, pending : Pending) -> Result<()> {
+        let mut map = self.pending.lock().unwrap();
+        if let Some(max_in_flight) = self.max_in_flight {
+            if map.len() >= max_in_flight {
+                return Err(Error::MaxInFlight);
+            }
+        }
+        map.insert(id, pending);
+        Ok(())
+    }
+
+    /// Issue a binary call. When `timeout` is given, the call fails with
+    /// [`Error::Timeout`] once it elapses instead of waiting for the
+    /// periodic timeout sweep, and the server is asked to abort it.
+    async fn call_with_buffer(
+        self : &Arc<Self>,
+        op : u32,
+        message : Message<'_>,
+        timeout : Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        let id = u64::from_le_bytes(rand::random::<[u8; 8]>());
+        let (sender,receiver) = oneshot();
+        let frame = to_vec((ReqHeader{op,id},message));
+
+        let _cancel = CancelOnDrop { inner : self.clone(), id };
+        self.submit(id, Pending::with_op(op, Arc::new(Box::new(move |result| {
+            let resp = match result {
+                Ok(data) => Ok(data.to_vec()),
+                Err(e) => Err(e),
+            };
+            // the receiver is gone if the call future has been dropped
+            let _ = sender.try_send(resp);
+        }))).with_timeout(timeout), PendingFrame::Binary(frame)).await?;
+        self.response(id, Some(op), receiver, timeout).await
+    }
+
+    /// Wait for the response to the call `id`. When `timeout` is given,
+    /// the call fails with [`Error::Timeout`] once it elapses instead of
+    /// waiting for the periodic timeout sweep; binary calls are then
+    /// aborted on the server.
+    async fn response(
+        self : &Arc<Self>,
+        id : u64,
+        op : Option<u32>,
+        receiver : Receiver<Result<Vec<u8>>>,
+        timeout : Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => { return receiver.recv().await?; }
+        };
+
+        let response = receiver.recv().fuse();
+        let delay = async_std::task::sleep(timeout).fuse();
+        pin_mut!(response, delay);
+        select! {
+            response = response => response?,
+            () = delay => {
+                let pending = self.pending.lock().unwrap().remove(&id);
+                if let (Some(_), Some(op)) = (pending, op) {
+                    self.stats.timeout(op);
+                    self.post_cancel(id);
+                }
+                Err(Error::Timeout)
+            }
+        }
+    }
+
+    /// Remove a pending call or an open stream, completing it with
+    /// [`Error::Cancelled`], and ask the server to abort it. Returns
+    /// `false` if the call is no longer pending.
+    pub(super) fn cancel(self : &Arc<Self>, id : u64) -> bool {
+        self.uploads.lock().unwrap().remove(&id);
+        let stream = self.streams.lock().unwrap().remove(&id);
+        if let Some(sender) = stream {
+            let _ = sender.try_send(Err(Error::Cancelled));
+            self.post_cancel(id);
+            return true;
+        }
+
+        let pending = self.pending.lock().unwrap().remove(&id);
+        let pending = match pending {
+            Some(pending) => pending,
+            None => { return false; }
+        };
+
+        if let Some(op) = pending.op {
+            self.stats.cancelled(op);
+            self.post_cancel(id);
+        }
+
+        (pending.callback)(Err(Error::Cancelled));
+        true
+    }
+
+    async fn handshake_step(self : &Arc<Self>, request : HandshakeRequest) -> Result<HandshakeResponse> {
+        let data = request.try_to_vec().map_err(|_| { Error::BorshSerialize })?;
+        let resp = self.call_with_buffer(CtlOp::Handshake as u32, Message::Request(&data), None).await?;
+        Ok(HandshakeResponse::try_from_slice(&resp).map_err(|e|Error::BorshDeserialize(e.to_string()))?)
+    }
+
+    /// Authenticate the connection using the configured credentials.
+    /// Returns `None` if no credentials have been configured.
+    async fn handshake(self : &Arc<Self>) -> Result<Option<RpcIdentity>> {
+        let credentials = self.credentials.lock().unwrap().clone();
+        let identity = match credentials {
+            None => { return Ok(None); },
+            Some(RpcCredentials::Token(token)) => {
+                match self.handshake_step(HandshakeRequest::Token(token)).await? {
+                    HandshakeResponse::Accepted(identity) => identity,
+                    _ => { return Err(Error::Handshake); }
+                }
+            },
+            Some(RpcCredentials::ChallengeResponse(respond)) => {
+                let challenge = match self.handshake_step(HandshakeRequest::Challenge).await? {
+                    HandshakeResponse::Challenge(challenge) => challenge,
+                    _ => { return Err(Error::Handshake); }
+                };
+                match self.handshake_step(HandshakeRequest::Response(respond(&challenge[..]))).await? {
+                    HandshakeResponse::Accepted(identity) => identity,
+                    _ => { return Err(Error::Handshake); }
+                }
+            }
+        };
+
+        *self.identity.lock().unwrap() = Some(identity.clone());
+        Ok(Some(identity))
+    }
+
+    fn handle_json_response(&self, text : &str) {
+        let responses = match serde_json::from_str::<serde_json::Value>(text) {
+            Ok(serde_json::Value::Array(batch)) => batch,
+            Ok(response) => vec![response],
+            Err(err) => {
+                log_error!("Failed to decode JSON-RPC server response: {}", err);
+                return;
+            }
+        };
+
+        for response in responses {
+            let response = match serde_json::from_value::<JsonRpcResponse>(response) {
+                Ok(response) => response,
+                Err(err) => {
+                    log_error!("Invalid JSON-RPC response: {}", err);
+                    continue;
+                }
+            };
+
+            let id = match response.id.as_u64() {
+                Some(id) => id,
+                None => {
+                    log_trace!("JSON-RPC response without a call id: {:?}", response.error);
+                    continue;
+                }
+            };
+
+            match self.pending.lock().unwrap().remove(&id) {
+                Some(pending) => {
+                    match response.error {
+                        Some(err) => {
+                            (pending.callback)(Err(Error::JsonRpc(err)));
+                        },
+                        None => {
+                            let result = response.result.unwrap_or(serde_json::Value::Null);
+                            match serde_json::to_vec(&result) {
+                                Ok(data) => { (pending.callback)(Ok(&data)); },
+                                Err(err) => { (pending.callback)(Err(err.into())); }
+                            }
+                        }
+                    }
+                },
+                None => {
+                    log_trace!("rpc callback with id {} not found", id);
+                }
+            }
+        }
+    }
+
+    /// Issue a JSON-RPC 2.0 request. Returns the serialized `result`
+    /// member of the response. Call ids are limited to 53 bits so
+    /// that they survive JSON number handling in other runtimes.
+    /// `timeout` overrides the client timeout as for binary calls.
+    pub(super) async fn call_json_with_value(
+        self : &Arc<Self>,
+        method : &str,
+        params : serde_json::Value,
+        timeout : Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        let id = u64::from_le_bytes(rand::random::<[u8; 8]>()) & 0x001f_ffff_ffff_ffff;
+        let request = serde_json::to_string(&JsonRpcRequest::new(method, params, Some(id.into())))?;
+        let (sender,receiver) = oneshot();
+
+        let _cancel = CancelOnDrop { inner : self.clone(), id };
+        self.submit(id, Pending::new(Arc::new(Box::new(move |result| {
+            let resp = match result {
+                Ok(data) => Ok(data.to_vec()),
+                Err(e) => Err(e),
+            };
+            // the receiver is gone if the call future has been dropped
+            let _ = sender.try_send(resp);
+        }))).with_timeout(timeout), PendingFrame::Text(request)).await?;
+        self.response(id, None, receiver, timeout).await
+    }
+
+    /// Post a JSON-RPC 2.0 notification (a request without an id).
+    pub(super) async fn notify_json_with_value(
+        &self,
+        method : &str,
+        params : serde_json::Value,
+    ) -> Result<()> {
+        if !self.ready.load(Ordering::SeqCst) {
+            return Err(WebSocketError::NotConnected.into());
+        }
+
+        let request = serde_json::to_string(&JsonRpcRequest::new(method, params, None))?;
+        self.ws.post(WebSocketMessage::Text(request)).await?;
+        Ok(())
+    }
+
+    fn handle_notification(&self, op : u32, data : &[u8]) {
+        let callback = self.notifications.lock().unwrap().get(&op).cloned();
+        match callback {
+            Some(callback) => {
+                callback(data);
+            },
+            None => {
+                log_trace!("rpc notification handler for op {} not found", op);
+            }
+        }
+    }
     
+    fn is_shutting_down(&self) -> bool {
+        matches!(self.state.get(), RpcClientState::Closing | RpcClientState::ShutDown)
+    }
+
+    /// Wait until no calls are pending or `deadline` elapses.
+    async fn drain(&self, deadline : Duration) {
+        let started = Instant::now();
+        while !self.pending.lock().unwrap().is_empty() && started.elapsed() < deadline {
+            async_std::task::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Fail all pending calls and open streams with [`Error::Shutdown`].
+    fn cancel_all(&self) {
+        // callers of `connect()` fail with `Error::Shutdown`
+        self.handshake_waiters.lock().unwrap().clear();
+
+        for (_, sender) in self.streams.lock().unwrap().drain() {
+            let _ = sender.try_send(Err(Error::Shutdown));
+        }
+        self.uploads.lock().unwrap().clear();
+
+        let cancelled : Vec<Pending> = self.pending.lock().unwrap().drain().map(|(_, pending)| pending).collect();
+        for pending in cancelled {
+            if let Some(op) = pending.op {
+                self.stats.cancelled(op);
+            }
+            (pending.callback)(Err(Error::Shutdown));
+        }
+    }
+
+    async fn stop_reconnect(&self) {
+        let stopped = self.reconnect_stopped.lock().unwrap().take();
+        if let Some(stopped) = stopped {
+            // fails once the task has exited and dropped its sender
+            let _ = stopped.recv().await;
+        }
+    }
+
     async fn stop_receiver(&self) -> Result<()> {
-        if self.receiver_is_running.load(Ordering::SeqCst) {
+        if !self.receiver_is_running.load(Ordering::SeqCst) {
             return Ok(());
         }
 
@@ -243,7 +1368,7 @@ where
     // Arc<Inner> : Send + Sync,
     Ops : TryInto<u32> + Send + Sync + 'static,
 {
-    inner: Arc<Inner>,
+    pub(super) inner: Arc<Inner>,
     _ops_ : std::marker::PhantomData<Ops>,
 }
 
@@ -252,9 +1377,13 @@ where
     Ops : Into<u32> + Send + Sync + 'static
 {
     pub fn new(url : &str) -> Result<RpcClient<Ops>> {
+        Self::new_with_options(url, RpcClientOptions::default())
+    }
+
+    pub fn new_with_options(url : &str, options : RpcClientOptions) -> Result<RpcClient<Ops>> {
 
         let client = RpcClient{
-            inner : Arc::new(Inner::new(url)?),
+            inner : Arc::new(Inner::new(url, options)?),
             _ops_ : std::marker::PhantomData,
         };
 
@@ -264,20 +1393,99 @@ where
         Ok(client)
     }
 
-    pub fn init_ctl(&self) -> Receiver<Ctl> {
-        let channel = unbounded();
-        let receiver = channel.1.clone();
-        *self.inner.ctl_channel.lock().unwrap() = Some(channel);
-        receiver
+    pub fn builder(url : &str) -> RpcClientBuilder<Ops> {
+        RpcClientBuilder::new(url)
+    }
+
+    /// Current connection state.
+    pub fn state(&self) -> RpcClientState {
+        self.inner.state.get()
+    }
+
+    /// Observe connection state transitions. Each call returns an
+    /// independent watcher.
+    pub fn watch(&self) -> RpcStateWatch {
+        self.inner.state.watch()
+    }
+
+    /// Receive connection events. Each call returns an independent
+    /// receiver; events are delivered to all open receivers.
+    pub fn events(&self) -> Receiver<RpcClientEvent> {
+        self.inner.events.subscribe()
+    }
+
+    /// Mark `op` as safe to repeat, so that its pending calls are
+    /// re-sent once the connection is re-established.
+    pub fn set_idempotent(&self, op : Ops, idempotent : bool) {
+        self.inner.set_idempotent(op.into(), idempotent);
     }
 
+    /// Configure credentials used to perform the authentication
+    /// handshake each time the connection is opened.
+    pub fn set_credentials(&self, credentials : Option<RpcCredentials>) {
+        *self.inner.credentials.lock().unwrap() = credentials;
+    }
+
+    /// Identity returned by the server during the last successful handshake.
+    pub fn identity(&self) -> Option<RpcIdentity> {
+        self.inner.identity.lock().unwrap().clone()
+    }
+
+    /// Connect to the server. The handshake is performed each time the
+    /// connection opens. When `block_until_connected` is set, this
+    /// function returns once the handshake has completed and the
+    /// connection has been restored, failing with
+    /// [`Error::HandshakeFailed`] if the handshake was rejected.
     pub async fn connect(&self, block_until_connected:bool) -> Result<Option<Listener>> {
-        Ok(self.inner.ws.connect(block_until_connected).await?)
+        if self.inner.is_shutting_down() {
+            return Err(Error::Shutdown);
+        }
+        self.inner.state.transition(RpcClientState::Closed, RpcClientState::Connecting);
+
+        let handshake = block_until_connected.then(|| self.inner.wait_for_handshake());
+        let result = self.inner.ws.connect(block_until_connected).await;
+        if result.is_err() {
+            self.inner.state.transition(RpcClientState::Connecting, RpcClientState::Closed);
+        }
+        let listener = result?;
+
+        if let Some(handshake) = handshake {
+            match handshake.recv().await {
+                Ok(Ok(())) => { },
+                Ok(Err(err)) => { return Err(Error::HandshakeFailed(err)); },
+                // the client has been shut down before the connection opened
+                Err(_) => { return Err(Error::Shutdown); }
+            }
+        }
+        Ok(listener)
     }
 
-    pub async fn shutdown(&self) -> Result<()> {
+    /// Shut the client down. In-flight calls are drained or cancelled
+    /// according to `mode`, after which the connection is closed and the
+    /// background tasks are stopped. Once this function returns, the
+    /// client is in the [`ShutDown`](RpcClientState::ShutDown) state and
+    /// further calls fail with [`Error::Shutdown`].
+    pub async fn shutdown(&self, mode : ShutdownMode) -> Result<()> {
+        match self.inner.state.get() {
+            RpcClientState::ShutDown => { return Ok(()); },
+            // another shutdown is in progress
+            RpcClientState::Closing => { return self.watch().wait_for(RpcClientState::ShutDown).await; },
+            _ => { self.inner.state.set(RpcClientState::Closing); }
+        }
+
+        if let ShutdownMode::Drain { deadline } = mode {
+            self.inner.drain(deadline).await;
+        }
+        self.inner.cancel_all();
+
+        if let Err(err) = self.inner.ws.disconnect().await {
+            log_trace!("RPC unable to disconnect: {}", err);
+        }
+        self.inner.stop_reconnect().await;
         self.inner.stop_timeout().await?;
         self.inner.stop_receiver().await?;
+
+        self.inner.state.set(RpcClientState::ShutDown);
         Ok(())
     }
 
@@ -285,22 +1493,96 @@ where
         self.inner.ws.is_open()
     }
 
+    /// Snapshot of per-op call statistics, keyed by op. Only binary
+    /// calls are tracked; JSON-RPC calls are not included.
+    pub fn stats(&self) -> AHashMap<u32, RpcOpStats> {
+        self.inner.stats.snapshot()
+    }
+
+    pub fn reset_stats(&self) {
+        self.inner.stats.reset();
+    }
+
+    /// Register a handler receiving raw notification data
+    /// posted by the server for the given `op`. Registering
+    /// a handler for the same op replaces the previous one.
+    pub fn notification_callback_with_buffer(
+        &self,
+        op : Ops,
+        callback : RpcNotificationFn
+    ) {
+        self.inner.notifications.lock().unwrap().insert(op.into(), callback);
+    }
+
+    /// Register a typed notification handler for the given `op`.
+    /// Notifications that fail to deserialize are logged and dropped.
+    pub fn notification<Msg>(
+        &self,
+        op : Ops,
+        callback : Arc<Box<(dyn Fn(Msg) + Sync + Send)>>
+    )
+    where
+        Msg : BorshDeserialize + Send + Sync + 'static,
+    {
+        self.notification_callback_with_buffer(op, Arc::new(Box::new(move |data| {
+            match Msg::try_from_slice(data) {
+                Ok(msg) => { callback(msg); },
+                Err(err) => {
+                    log_error!("RPC unable to deserialize notification: {}", err);
+                }
+            }
+        })));
+    }
+
+    /// Remove a notification handler previously registered for `op`.
+    pub fn remove_notification(&self, op : Ops) {
+        self.inner.notifications.lock().unwrap().remove(&op.into());
+    }
+
+    /// Issue a call completing through `callback`. Returns the request
+    /// id, which can be passed to [`cancel()`](Self::cancel).
     pub async fn call_callback_with_buffer(
         &self,
         op : Ops,
         message : Message<'_>,
         callback : RpcResponseFn
-    ) -> Result<()> {
-        if !self.is_open() {
-            return Err(WebSocketError::NotConnected.into());
-        }
+    ) -> Result<u64> {
+        self.submit_callback(op, message, callback, None).await
+    }
 
-        let mut pending = self.inner.pending.lock().unwrap();
+    /// Issue a call completing through `callback`, which receives
+    /// [`Error::Timeout`] once `timeout` elapses, overriding the client
+    /// timeout. The timeout is enforced by the periodic timeout sweep.
+    pub async fn call_callback_with_buffer_and_timeout(
+        &self,
+        op : Ops,
+        message : Message<'_>,
+        timeout : Duration,
+        callback : RpcResponseFn
+    ) -> Result<u64> {
+        self.submit_callback(op, message, callback, Some(timeout)).await
+    }
+
+    async fn submit_callback(
+        &self,
+        op : Ops,
+        message : Message<'_>,
+        callback : RpcResponseFn,
+        timeout : Option<Duration>,
+    ) -> Result<u64> {
+        let op = op.into();
         let id = u64::from_le_bytes(rand::random::<[u8; 8]>());
-        pending.insert(id,Pending::new(callback));
-        drop(pending);
-        self.inner.ws.post(to_ws_msg((ReqHeader{op : op.into(),id},message))).await?;
-        Ok(())
+        let frame = to_vec((ReqHeader{op,id},message));
+        self.inner.submit(id, Pending::with_op(op, callback).with_timeout(timeout), PendingFrame::Binary(frame)).await?;
+        Ok(id)
+    }
+
+    /// Cancel a pending call. Its callback receives [`Error::Cancelled`]
+    /// and the server is asked to abort the request. Dropping the future
+    /// returned by [`call_async_with_buffer()`](Self::call_async_with_buffer)
+    /// cancels the call automatically.
+    pub fn cancel(&self, id : u64) -> bool {
+        self.inner.cancel(id)
     }
 
     pub async fn call_async_with_buffer(
@@ -308,27 +1590,18 @@ where
         op : Ops,
         message : Message<'_>,
     ) -> Result<Vec<u8>> {
-        if !self.is_open() {
-            return Err(WebSocketError::NotConnected.into());
-        }
-
-        let id = u64::from_le_bytes(rand::random::<[u8; 8]>());
-        let (sender,receiver) = oneshot();
-
-        {
-            let mut pending = self.inner.pending.lock().unwrap();
-            pending.insert(id,Pending::new(Arc::new(Box::new(move |result| {
-                let resp = match result {
-                    Ok(data) => Ok(data.to_vec()),
-                    Err(e) => Err(e),
-                };
-                sender.try_send(resp).unwrap();
-            }))));
-            drop(pending);
-        }
+        self.inner.call_with_buffer(op.into(), message, None).await
+    }
 
-        self.inner.ws.post(to_ws_msg((ReqHeader{op : op.into(),id},message))).await?;
-        receiver.recv().await?
+    /// Issue a call that fails with [`Error::Timeout`] once `timeout`
+    /// elapses, overriding the client timeout.
+    pub async fn call_async_with_buffer_and_timeout(
+        &self,
+        op : Ops,
+        message : Message<'_>,
+        timeout : Duration,
+    ) -> Result<Vec<u8>> {
+        self.inner.call_with_buffer(op.into(), message, Some(timeout)).await
     }
 
     pub async fn call<Req,Resp>(
@@ -345,6 +1618,199 @@ where
         Ok(Resp::try_from_slice(&resp).map_err(|e|Error::BorshDeserialize(e.to_string()))?)
     }
 
+    /// Issue a call that fails with [`Error::Timeout`] once `timeout`
+    /// elapses, overriding the client timeout.
+    pub async fn call_with_timeout<Req,Resp>(
+        &self,
+        op : Ops,
+        req : Req,
+        timeout : Duration,
+    ) -> Result<Resp>
+    where
+        Req : BorshSerialize + Send + Sync + 'static,
+        Resp : BorshDeserialize + Send + Sync +'static,
+    {
+        let data = req.try_to_vec().map_err(|_| { Error::BorshSerialize })?;
+        let resp = self.call_async_with_buffer_and_timeout(op, Message::Request(&data), timeout).await?;
+        Ok(Resp::try_from_slice(&resp).map_err(|e|Error::BorshDeserialize(e.to_string()))?)
+    }
+
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn pending(op : u32, frame : Option<u8>, generation : Option<u64>) -> Pending {
+        let pending = Pending::with_op(op, Arc::new(Box::new(|_ : Result<&[u8]>| { })))
+            .with_frame(frame.map(|byte| PendingFrame::Binary(vec![byte])));
+        match generation {
+            Some(generation) => pending.with_generation(generation),
+            None => pending,
+        }
+    }
+
+    #[test]
+    fn replay_skips_calls_posted_on_the_current_connection() {
+        let mut map = AHashMap::new();
+        map.insert(1, pending(1, Some(1), Some(0)));
+        map.insert(2, pending(1, Some(2), Some(1)));
+        map.insert(3, pending(1, Some(3), None));
+        map.insert(4, pending(1, None, Some(0)));
+
+        let mut frames : Vec<Vec<u8>> = replay_frames(&mut map, 1, |_| true)
+            .into_iter()
+            .map(|replay| match replay.msg {
+                WebSocketMessage::Binary(data) => data,
+                _ => panic!("unexpected frame"),
+            })
+            .collect();
+        frames.sort();
+        assert_eq!(frames, vec![vec![1], vec![3]]);
+        assert!(map.values().all(|pending| pending.generation == Some(1) || pending.frame.is_none()));
+
+        // a second restore of the same connection sends nothing
+        assert!(replay_frames(&mut map, 1, |_| true).is_empty());
+    }
+
+    #[test]
+    fn replayed_calls_not_held_release_their_frame() {
+        let mut map = AHashMap::new();
+        map.insert(1, pending(1, Some(1), None));
+        map.insert(2, pending(2, Some(2), None));
+
+        assert_eq!(replay_frames(&mut map, 1, |op| op == Some(2)).len(), 2);
+        assert!(map[&1].frame.is_none());
+        assert!(map[&2].frame.is_some());
+
+        // the held call is replayed on the next connection
+        assert_eq!(replay_frames(&mut map, 2, |op| op == Some(2)).len(), 1);
+    }
+
+    fn inner(options : RpcClientOptions) -> Arc<Inner> {
+        Arc::new(Inner::new("ws://127.0.0.1:1", options).unwrap())
+    }
 
+    fn recorder() -> (RpcResponseFn, Receiver<Result<Vec<u8>>>) {
+        let (sender, receiver) = unbounded();
+        (Arc::new(Box::new(move |result : Result<&[u8]>| {
+            let _ = sender.try_send(result.map(|data| data.to_vec()));
+        })), receiver)
+    }
+
+    #[test]
+    fn calls_are_rejected_while_disconnected() {
+        let inner = inner(RpcClientOptions::default());
+        let (callback, _) = recorder();
+        let result = block_on(inner.submit(1, Pending::with_op(1, callback), PendingFrame::Binary(vec![1])));
+        assert!(matches!(result, Err(Error::WebSocketError(_))));
+        assert!(inner.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn calls_are_buffered_during_an_outage() {
+        let inner = inner(RpcClientOptions { outage : OutagePolicy::Buffer, ..Default::default() });
+        let (callback, _) = recorder();
+        block_on(inner.submit(1, Pending::with_op(1, callback), PendingFrame::Binary(vec![1]))).unwrap();
+
+        let pending = inner.pending.lock().unwrap();
+        assert!(pending[&1].frame.is_some());
+        assert_eq!(pending[&1].generation, None);
+    }
+
+    #[test]
+    fn calls_are_counted_once_when_first_sent() {
+        let inner = inner(RpcClientOptions { outage : OutagePolicy::Buffer, ..Default::default() });
+        let (callback, _) = recorder();
+        let frame = to_vec((ReqHeader { op : 1, id : 1 }, Message::Request(&[1, 2, 3])));
+        block_on(inner.submit(1, Pending::with_op(1, callback), PendingFrame::Binary(frame))).unwrap();
+        // buffered calls have not been sent yet
+        assert!(inner.stats.snapshot().is_empty());
+
+        let mut pending = inner.pending.lock().unwrap();
+        let replay = replay_frames(&mut pending, 1, |_| true);
+        assert_eq!(replay.len(), 1);
+        assert_eq!(replay[0].first, Some((1, 3)));
+
+        // replaying the call on the next connection does not count it again
+        let replay = replay_frames(&mut pending, 2, |_| true);
+        assert_eq!(replay.len(), 1);
+        assert_eq!(replay[0].first, None);
+    }
+
+    #[test]
+    fn control_requests_are_never_buffered() {
+        let inner = inner(RpcClientOptions { outage : OutagePolicy::Buffer, ..Default::default() });
+        let (callback, _) = recorder();
+        let result = block_on(inner.submit(1, Pending::with_op(CtlOp::Subscribe as u32, callback), PendingFrame::Binary(vec![1])));
+        assert!(matches!(result, Err(Error::WebSocketError(_))));
+    }
+
+    #[test]
+    fn calls_are_rejected_once_shutting_down() {
+        let inner = inner(RpcClientOptions { outage : OutagePolicy::Buffer, ..Default::default() });
+        inner.state.set(RpcClientState::Closing);
+        let (callback, _) = recorder();
+        let result = block_on(inner.submit(1, Pending::with_op(1, callback), PendingFrame::Binary(vec![1])));
+        assert!(matches!(result, Err(Error::Shutdown)));
+    }
+
+    #[test]
+    fn drain_waits_until_the_deadline() {
+        let inner = inner(RpcClientOptions::default());
+        block_on(inner.drain(Duration::from_secs(60)));
+
+        let (callback, receiver) = recorder();
+        inner.insert_pending(1, Pending::with_op(1, callback)).unwrap();
+        let started = Instant::now();
+        block_on(inner.drain(Duration::from_millis(30)));
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn handshake_failure_fails_buffered_calls() {
+        let inner = inner(RpcClientOptions { outage : OutagePolicy::Buffer, ..Default::default() });
+        let events = inner.events.subscribe();
+        let handshake = inner.wait_for_handshake();
+        let (callback, receiver) = recorder();
+        block_on(inner.submit(1, Pending::with_op(1, callback), PendingFrame::Binary(vec![1]))).unwrap();
+
+        inner.fail_handshake("rejected");
+        inner.complete_handshake(0, Err("rejected".to_string()));
+
+        assert!(matches!(receiver.try_recv().unwrap(), Err(Error::HandshakeFailed(err)) if err == "rejected"));
+        assert!(inner.pending.lock().unwrap().is_empty());
+        assert_eq!(events.try_recv().unwrap(), RpcClientEvent::HandshakeFailed { error : "rejected".to_string() });
+        assert_eq!(handshake.try_recv().unwrap(), Err("rejected".to_string()));
+        assert!(inner.handshake_failed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn handshake_outcome_is_reported_to_its_connection_only() {
+        let inner = inner(RpcClientOptions::default());
+        let first = inner.wait_for_handshake();
+        inner.generation.fetch_add(1, Ordering::SeqCst);
+        let second = inner.wait_for_handshake();
+
+        inner.complete_handshake(0, Err("rejected".to_string()));
+        assert_eq!(first.try_recv().unwrap(), Err("rejected".to_string()));
+        assert!(second.try_recv().is_err());
+
+        inner.complete_handshake(1, Ok(()));
+        assert_eq!(second.try_recv().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn cancel_fails_in_flight_calls() {
+        let inner = inner(RpcClientOptions::default());
+        let (callback, receiver) = recorder();
+        inner.insert_pending(1, Pending::with_op(1, callback)).unwrap();
+        inner.cancel_all();
+
+        assert!(matches!(receiver.try_recv().unwrap(), Err(Error::Shutdown)));
+        assert!(inner.pending.lock().unwrap().is_empty());
+        assert_eq!(inner.stats.snapshot()[&1].cancelled, 1);
+    }
+}
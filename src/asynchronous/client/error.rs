@@ -5,6 +5,7 @@ use workflow_websocket::client::error::Error as WebSocketError;
 use wasm_bindgen::JsValue;
 use workflow_core::channel::{RecvError,SendError};
 use crate::asynchronous::error::RpcResponseError;
+use crate::asynchronous::jsonrpc::JsonRpcError;
 use serde::*;
 // use borsh::*;
 
@@ -37,9 +38,44 @@ pub enum Error {
     /// Response produced an unknown status code
     #[error("RPC: status code {0}")]
     StatusCode(u32),
+    /// Server rejected the request or the handshake credentials
+    #[error("RPC: unauthorized {0:?}")]
+    Unauthorized(RpcResponseError),
+    /// Server was unable to decode the request header
+    #[error("RPC: malformed request header")]
+    MalformedHeader,
+    /// Server does not recognize the request op
+    #[error("RPC: unknown op")]
+    UnknownOp,
+    /// The connection was lost before a response was received
+    #[error("RPC: disconnected")]
+    Disconnected,
+    /// The maximum number of pending calls has been reached
+    #[error("RPC: too many calls in flight")]
+    MaxInFlight,
+    /// RPC call was cancelled before a response was received
+    #[error("RPC: call cancelled")]
+    Cancelled,
+    /// The client has been shut down
+    #[error("RPC: client shut down")]
+    Shutdown,
+    /// The subscribe request issued on behalf of another subscriber
+    /// of the same topic has failed
+    #[error("RPC: subscription failed: {0}")]
+    SubscriptionFailed(String),
+    /// Server responded to the handshake with an unexpected message
+    #[error("RPC: unexpected handshake response")]
+    Handshake,
+    /// The handshake performed when the connection opened has failed;
+    /// the connection has been closed
+    #[error("RPC: handshake failed: {0}")]
+    HandshakeFailed(String),
     /// RPC call executed successfully but produced an error response
     #[error("RPC: response error {0:?}")]
     RpcCall(RpcResponseError),
+    /// JSON-RPC call executed but produced an error object
+    #[error("RPC: JSON-RPC error {}: {}", .0.code, .0.message)]
+    JsonRpc(JsonRpcError),
     /// Unable to serialize borsh data    
     #[error("RPC: borsh serialization error")]
     BorshSerialize,
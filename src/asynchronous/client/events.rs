@@ -0,0 +1,61 @@
+use std::sync::Mutex;
+use workflow_core::time::Duration;
+use workflow_core::channel::*;
+
+/// Connection events reported by [`RpcClient::events()`](super::RpcClient::events).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RpcClientEvent {
+    /// The connection has been opened
+    Connected,
+    /// The connection has been lost or closed
+    Disconnected,
+    /// A reconnection attempt will be made once `delay` elapses
+    Reconnecting { attempt : u32, delay : Duration },
+    /// Reconnection has been abandoned after `attempts` attempts;
+    /// pending calls have been failed
+    ReconnectFailed { attempts : u32 },
+    /// The handshake performed when the connection opened has failed.
+    /// Pending calls have been failed and the connection is closed
+    /// without reconnecting.
+    HandshakeFailed { error : String },
+}
+
+/// Fan-out of connection events to any number of observers.
+#[derive(Default)]
+pub(crate) struct RpcEvents {
+    senders : Mutex<Vec<Sender<RpcClientEvent>>>,
+}
+
+impl RpcEvents {
+    pub fn subscribe(&self) -> Receiver<RpcClientEvent> {
+        let (sender, receiver) = unbounded();
+        self.senders.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Deliver `event` to all observers, dropping those whose
+    /// receivers are gone.
+    pub fn emit(&self, event : RpcClientEvent) {
+        self.senders.lock().unwrap().retain(|sender| sender.try_send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_reach_every_observer() {
+        let events = RpcEvents::default();
+        let first = events.subscribe();
+        let second = events.subscribe();
+        events.emit(RpcClientEvent::Connected);
+        assert_eq!(first.try_recv().unwrap(), RpcClientEvent::Connected);
+        assert_eq!(second.try_recv().unwrap(), RpcClientEvent::Connected);
+
+        drop(second);
+        events.emit(RpcClientEvent::Disconnected);
+        assert_eq!(events.senders.lock().unwrap().len(), 1);
+        assert_eq!(first.try_recv().unwrap(), RpcClientEvent::Disconnected);
+    }
+}
@@ -1,6 +1,10 @@
 pub use super::error::*;
 pub use super::message::*;
 pub use super::ops::*;
+pub use super::auth::*;
+pub use super::jsonrpc::*;
+pub use super::stream::*;
+pub use super::pubsub::*;
 
 mod client;
 pub use self::client::*;
@@ -8,8 +12,30 @@ pub use self::client::*;
 // mod with_borsh;
 // pub use self::with_borsh::*;
 
-// mod with_serde;
-// pub use self::with_serde::*;
+mod with_serde;
+pub use self::with_serde::*;
+
+mod options;
+pub use self::options::*;
+
+mod stream;
+pub use self::stream::*;
+
+mod upload;
+pub use self::upload::*;
+
+mod pubsub;
+pub use self::pubsub::*;
+
+mod state;
+pub use self::state::{RpcClientState, RpcStateWatch};
+
+mod events;
+pub use self::events::RpcClientEvent;
+
+mod stats;
+pub use self::stats::RpcOpStats;
+pub use crate::asynchronous::histogram::*;
 
 pub mod error;
 // pub use self::error::*;
@@ -0,0 +1,257 @@
+use std::marker::PhantomData;
+use workflow_core::time::Duration;
+use workflow_websocket::client::Settings as WebSocketSettings;
+use super::*;
+use super::result::Result;
+
+/// Client behavior once the connection has been lost.
+#[derive(Clone, Debug, Default)]
+pub enum ReconnectPolicy {
+    /// Reconnection is performed by the underlying WebSocket.
+    #[default]
+    Default,
+    /// The connection is not re-established once lost.
+    Disabled,
+    /// The client re-establishes the connection itself,
+    /// waiting between attempts as configured.
+    Backoff(Backoff),
+}
+
+/// Delays between reconnection attempts, growing from `initial` by
+/// `multiplier` on each attempt up to `max`. Each delay is varied at
+/// random by up to `jitter` (a fraction of the delay) so that clients
+/// dropped together do not reconnect together.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    pub initial : Duration,
+    pub max : Duration,
+    pub multiplier : f64,
+    pub jitter : f64,
+    /// Number of attempts after which reconnection is abandoned
+    pub max_attempts : Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial : Duration::from_millis(500),
+            max : Duration::from_millis(30_000),
+            multiplier : 2.0,
+            jitter : 0.2,
+            max_attempts : None,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay preceding the zero-based reconnection `attempt`,
+    /// never exceeding `max`.
+    pub fn delay(&self, attempt : u32) -> Duration {
+        self.delay_with_jitter(attempt, rand::random::<f64>() * 2.0 - 1.0)
+    }
+
+    /// Delay for `attempt` with the jitter factor `unit` in `-1.0..=1.0`.
+    fn delay_with_jitter(&self, attempt : u32, unit : f64) -> Duration {
+        let max = self.max.as_secs_f64();
+        let delay = (self.initial.as_secs_f64() * self.multiplier.max(1.0).powi(attempt.min(64) as i32)).min(max);
+        let jitter = self.jitter.clamp(0.0, 1.0) * unit;
+        // jitter is applied before clamping so that it can not push the
+        // delay past `max`; non-finite values fall back to `max`
+        Duration::try_from_secs_f64((delay * (1.0 + jitter)).clamp(0.0, max)).unwrap_or(self.max)
+    }
+}
+
+/// Handling of calls issued while the connection is down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutagePolicy {
+    /// Calls fail with a `NotConnected` WebSocket error.
+    #[default]
+    Reject,
+    /// Calls are buffered and sent once the connection is
+    /// re-established. Buffered calls remain subject to their
+    /// timeouts. Streams, uploads and subscriptions are rejected.
+    Buffer,
+}
+
+/// Handling of pending calls when the connection is lost.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DisconnectPolicy {
+    /// Pending calls fail with [`Error::Disconnected`](super::error::Error::Disconnected).
+    #[default]
+    Fail,
+    /// Pending calls are held and re-sent once the connection is
+    /// re-established. Held calls remain subject to their timeouts.
+    /// Streams and uploads are failed regardless.
+    Hold,
+}
+
+/// Handling of in-flight calls by [`RpcClient::shutdown()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Wait for in-flight calls to complete until `deadline` elapses.
+    /// Calls still pending then fail with [`Error::Shutdown`](super::error::Error::Shutdown).
+    Drain { deadline : Duration },
+    /// In-flight calls fail immediately with [`Error::Shutdown`](super::error::Error::Shutdown).
+    Cancel,
+}
+
+/// Options applied when creating an [`RpcClient`].
+pub struct RpcClientOptions {
+    /// Timeout applied to calls that do not specify their own
+    pub timeout : Duration,
+    /// Interval at which pending calls are checked for timeouts
+    pub sweep_interval : Duration,
+    pub ws_settings : WebSocketSettings,
+    /// Maximum number of pending calls; further calls fail with
+    /// [`Error::MaxInFlight`](super::error::Error::MaxInFlight)
+    pub max_in_flight : Option<usize>,
+    pub reconnect : ReconnectPolicy,
+    pub on_disconnect : DisconnectPolicy,
+    pub outage : OutagePolicy,
+    /// Ops whose pending calls are re-sent after a reconnect
+    /// regardless of `on_disconnect`
+    pub idempotent : Vec<u32>,
+    /// Number of stream items the server may send ahead of the consumer
+    pub stream_window : u32,
+    /// Number of publications buffered for each subscriber; further
+    /// publications are dropped until the subscriber catches up
+    pub subscription_capacity : usize,
+}
+
+impl Default for RpcClientOptions {
+    fn default() -> Self {
+        RpcClientOptions {
+            timeout : Duration::from_millis(60_000),
+            sweep_interval : Duration::from_millis(5_000),
+            ws_settings : WebSocketSettings::default(),
+            max_in_flight : None,
+            reconnect : ReconnectPolicy::default(),
+            on_disconnect : DisconnectPolicy::default(),
+            outage : OutagePolicy::default(),
+            idempotent : Vec::new(),
+            stream_window : DEFAULT_STREAM_WINDOW,
+            subscription_capacity : DEFAULT_SUBSCRIPTION_CAPACITY,
+        }
+    }
+}
+
+/// Builder for [`RpcClient`], obtained from [`RpcClient::builder()`].
+///
+/// ```ignore
+/// let rpc = RpcClient::<Ops>::builder("ws://127.0.0.1:8080")
+///     .timeout(Duration::from_secs(10))
+///     .max_in_flight(256)
+///     .build()?;
+/// ```
+pub struct RpcClientBuilder<Ops> {
+    url : String,
+    options : RpcClientOptions,
+    _ops_ : PhantomData<Ops>,
+}
+
+impl<Ops> RpcClientBuilder<Ops>
+where
+    Ops : Into<u32> + Send + Sync + 'static
+{
+    pub(super) fn new(url : &str) -> Self {
+        RpcClientBuilder {
+            url : url.to_string(),
+            options : RpcClientOptions::default(),
+            _ops_ : PhantomData,
+        }
+    }
+
+    pub fn timeout(mut self, timeout : Duration) -> Self {
+        self.options.timeout = timeout;
+        self
+    }
+
+    pub fn sweep_interval(mut self, interval : Duration) -> Self {
+        self.options.sweep_interval = interval;
+        self
+    }
+
+    pub fn ws_settings(mut self, settings : WebSocketSettings) -> Self {
+        self.options.ws_settings = settings;
+        self
+    }
+
+    pub fn max_in_flight(mut self, max_in_flight : usize) -> Self {
+        self.options.max_in_flight = Some(max_in_flight);
+        self
+    }
+
+    pub fn reconnect(mut self, policy : ReconnectPolicy) -> Self {
+        self.options.reconnect = policy;
+        self
+    }
+
+    pub fn on_disconnect(mut self, policy : DisconnectPolicy) -> Self {
+        self.options.on_disconnect = policy;
+        self
+    }
+
+    pub fn outage(mut self, policy : OutagePolicy) -> Self {
+        self.options.outage = policy;
+        self
+    }
+
+    /// Mark `op` as safe to repeat; its pending calls are re-sent
+    /// once the connection is re-established.
+    pub fn idempotent(mut self, op : Ops) -> Self {
+        self.options.idempotent.push(op.into());
+        self
+    }
+
+    pub fn stream_window(mut self, window : u32) -> Self {
+        self.options.stream_window = window;
+        self
+    }
+
+    pub fn subscription_capacity(mut self, capacity : usize) -> Self {
+        self.options.subscription_capacity = capacity;
+        self
+    }
+
+    pub fn build(self) -> Result<RpcClient<Ops>> {
+        RpcClient::new_with_options(&self.url, self.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_from_initial() {
+        let backoff = Backoff { jitter : 0.0, ..Default::default() };
+        assert_eq!(backoff.delay(0), Duration::from_millis(500));
+        assert_eq!(backoff.delay(1), Duration::from_millis(1_000));
+        assert_eq!(backoff.delay(3), Duration::from_millis(4_000));
+    }
+
+    #[test]
+    fn backoff_never_exceeds_max() {
+        let backoff = Backoff::default();
+        for attempt in [0, 5, 6, 64, u32::MAX] {
+            assert!(backoff.delay_with_jitter(attempt, 1.0) <= backoff.max);
+            assert!(backoff.delay(attempt) <= backoff.max);
+        }
+        assert_eq!(backoff.delay_with_jitter(u32::MAX, 1.0), backoff.max);
+    }
+
+    #[test]
+    fn backoff_handles_degenerate_settings() {
+        let backoff = Backoff {
+            initial : Duration::MAX,
+            max : Duration::MAX,
+            multiplier : f64::INFINITY,
+            jitter : f64::NAN,
+            max_attempts : None,
+        };
+        assert_eq!(backoff.delay(10), Duration::MAX);
+
+        let backoff = Backoff { jitter : 5.0, ..Default::default() };
+        assert_eq!(backoff.delay_with_jitter(0, -1.0), Duration::ZERO);
+    }
+}
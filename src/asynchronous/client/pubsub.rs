@@ -0,0 +1,76 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use borsh::BorshDeserialize;
+use futures::stream::Stream;
+use workflow_core::channel::Receiver;
+use super::*;
+use super::error::Error;
+use super::result::Result;
+
+/// Stream of messages published to a topic. The subscription is
+/// renewed automatically when the client reconnects and is removed
+/// when the stream is dropped.
+pub struct RpcSubscription<Msg> {
+    inner : Arc<Inner>,
+    id : u64,
+    topic : String,
+    receiver : Pin<Box<Receiver<Vec<u8>>>>,
+    decode : fn(Vec<u8>) -> Result<Msg>,
+}
+
+impl<Msg> RpcSubscription<Msg> {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+impl<Msg> Stream for RpcSubscription<Msg> {
+    type Item = Result<Msg>;
+
+    fn poll_next(mut self : Pin<&mut Self>, cx : &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.receiver.as_mut().poll_next(cx) {
+            Poll::Ready(Some(data)) => Poll::Ready(Some((self.decode)(data))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<Msg> Drop for RpcSubscription<Msg> {
+    fn drop(&mut self) {
+        self.inner.unsubscribe(&self.topic, self.id);
+    }
+}
+
+impl<Ops> RpcClient<Ops>
+where
+    Ops : Into<u32> + Send + Sync + 'static
+{
+    /// Subscribe to `topic`, receiving raw published data.
+    pub async fn subscribe_with_buffer(&self, topic : &str) -> Result<RpcSubscription<Vec<u8>>> {
+        let (id, receiver) = self.inner.subscribe(topic).await?;
+        Ok(RpcSubscription {
+            inner : self.inner.clone(),
+            id,
+            topic : topic.to_string(),
+            receiver : Box::pin(receiver),
+            decode : Ok,
+        })
+    }
+
+    /// Subscribe to `topic`, receiving deserialized messages.
+    pub async fn subscribe<Msg>(&self, topic : &str) -> Result<RpcSubscription<Msg>>
+    where
+        Msg : BorshDeserialize + Send + Sync + 'static,
+    {
+        let (id, receiver) = self.inner.subscribe(topic).await?;
+        Ok(RpcSubscription {
+            inner : self.inner.clone(),
+            id,
+            topic : topic.to_string(),
+            receiver : Box::pin(receiver),
+            decode : |data| Msg::try_from_slice(&data).map_err(|e| Error::BorshDeserialize(e.to_string())),
+        })
+    }
+}
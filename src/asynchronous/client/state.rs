@@ -0,0 +1,195 @@
+use std::sync::{Arc, Mutex};
+use workflow_core::channel::*;
+use super::result::Result;
+
+/// Connection state of an [`RpcClient`](super::RpcClient).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RpcClientState {
+    /// A connection is being established
+    Connecting,
+    /// The connection is open
+    Open,
+    /// The connection has been lost and is being re-established
+    Reconnecting,
+    /// The client is shutting down
+    Closing,
+    /// The connection is closed and will not be re-established
+    /// unless `connect()` is called
+    Closed,
+    /// The client has been shut down
+    ShutDown,
+}
+
+/// Current client state together with the observers to be
+/// notified of its transitions.
+pub(crate) struct RpcStateCell {
+    state : Mutex<RpcClientState>,
+    watchers : Mutex<Vec<Sender<RpcClientState>>>,
+}
+
+impl RpcStateCell {
+    pub fn new(state : RpcClientState) -> Self {
+        RpcStateCell {
+            state : Mutex::new(state),
+            watchers : Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn get(&self) -> RpcClientState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Transition to `state`, notifying observers if the state has
+    /// changed. Returns the previous state.
+    pub fn set(&self, state : RpcClientState) -> RpcClientState {
+        let mut current = self.state.lock().unwrap();
+        let previous = std::mem::replace(&mut *current, state);
+        if previous != state {
+            self.notify(state);
+        }
+        previous
+    }
+
+    /// Transition to `state` only if the current state is `from`.
+    pub fn transition(&self, from : RpcClientState, state : RpcClientState) -> bool {
+        let mut current = self.state.lock().unwrap();
+        if *current != from || from == state {
+            return false;
+        }
+        *current = state;
+        self.notify(state);
+        true
+    }
+
+    /// Deliver a transition to the observers. Called with the state
+    /// locked, so that observers receive transitions in the order
+    /// they were made and the last one received is the current state.
+    fn notify(&self, state : RpcClientState) {
+        self.watchers.lock().unwrap().retain(|sender| sender.try_send(state).is_ok());
+    }
+
+    pub fn watch(self : &Arc<Self>) -> RpcStateWatch {
+        let (sender, receiver) = unbounded();
+        self.watchers.lock().unwrap().push(sender);
+        RpcStateWatch { cell : self.clone(), receiver }
+    }
+}
+
+/// Observer of client state transitions, obtained from
+/// [`RpcClient::watch()`](super::RpcClient::watch). Any number of
+/// watchers may be active; each receives every transition made
+/// after its creation.
+pub struct RpcStateWatch {
+    cell : Arc<RpcStateCell>,
+    receiver : Receiver<RpcClientState>,
+}
+
+impl RpcStateWatch {
+    /// Current state of the client.
+    pub fn state(&self) -> RpcClientState {
+        self.cell.get()
+    }
+
+    /// Wait for the next state transition, returning the new state.
+    pub async fn changed(&self) -> Result<RpcClientState> {
+        Ok(self.receiver.recv().await?)
+    }
+
+    /// Wait until the client reaches `state`. Returns immediately
+    /// if the client is already in that state.
+    pub async fn wait_for(&self, state : RpcClientState) -> Result<()> {
+        // transitions preceding the current state are stale
+        while self.receiver.try_recv().is_ok() { }
+        if self.cell.get() == state {
+            return Ok(());
+        }
+
+        while self.changed().await? != state { }
+        Ok(())
+    }
+
+    /// Wait until the client leaves `state`, returning the state
+    /// it is in by then. Returns immediately if it is not in `state`.
+    pub(crate) async fn left(&self, state : RpcClientState) -> Result<RpcClientState> {
+        loop {
+            let current = self.cell.get();
+            if current != state {
+                return Ok(current);
+            }
+            self.changed().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn transition_requires_the_expected_state() {
+        let cell = RpcStateCell::new(RpcClientState::Closing);
+        assert!(!cell.transition(RpcClientState::Connecting, RpcClientState::Open));
+        assert!(!cell.transition(RpcClientState::Reconnecting, RpcClientState::Open));
+        assert_eq!(cell.get(), RpcClientState::Closing);
+
+        assert!(cell.transition(RpcClientState::Closing, RpcClientState::ShutDown));
+        assert!(!cell.transition(RpcClientState::ShutDown, RpcClientState::ShutDown));
+        assert_eq!(cell.set(RpcClientState::Closed), RpcClientState::ShutDown);
+    }
+
+    #[test]
+    fn watchers_observe_every_transition() {
+        let cell = Arc::new(RpcStateCell::new(RpcClientState::Closed));
+        let watch = cell.watch();
+        cell.transition(RpcClientState::Closed, RpcClientState::Connecting);
+        cell.set(RpcClientState::Connecting);
+        cell.set(RpcClientState::Open);
+
+        block_on(async {
+            assert_eq!(watch.changed().await.unwrap(), RpcClientState::Connecting);
+            assert_eq!(watch.changed().await.unwrap(), RpcClientState::Open);
+        });
+        assert!(watch.receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn concurrent_transitions_are_observed_in_order() {
+        let cell = Arc::new(RpcStateCell::new(RpcClientState::Closed));
+        let watch = cell.watch();
+        let threads : Vec<_> = [RpcClientState::Open, RpcClientState::Reconnecting, RpcClientState::Closed]
+            .into_iter()
+            .map(|state| {
+                let cell = cell.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        cell.set(state);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut previous = RpcClientState::Closed;
+        while let Ok(state) = watch.receiver.try_recv() {
+            assert_ne!(state, previous);
+            previous = state;
+        }
+        assert_eq!(previous, cell.get());
+    }
+
+    #[test]
+    fn wait_for_ignores_stale_transitions() {
+        let cell = Arc::new(RpcStateCell::new(RpcClientState::Open));
+        let watch = cell.watch();
+        cell.set(RpcClientState::Closing);
+        cell.set(RpcClientState::ShutDown);
+
+        block_on(async {
+            watch.wait_for(RpcClientState::ShutDown).await.unwrap();
+            assert_eq!(watch.left(RpcClientState::Reconnecting).await.unwrap(), RpcClientState::ShutDown);
+        });
+    }
+}
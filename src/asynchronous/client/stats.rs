@@ -0,0 +1,96 @@
+use std::sync::Mutex;
+use ahash::AHashMap;
+use workflow_core::time::Duration;
+use crate::asynchronous::histogram::Histogram;
+use crate::asynchronous::message::CtlOp;
+
+/// Call statistics collected for a single op.
+#[derive(Debug, Clone, Default)]
+pub struct RpcOpStats {
+    pub calls : u64,
+    pub successes : u64,
+    pub errors : u64,
+    pub timeouts : u64,
+    pub cancelled : u64,
+    pub bytes_sent : u64,
+    pub bytes_received : u64,
+    /// Round-trip latency of calls that received a response
+    pub latency : Histogram,
+}
+
+/// Per-op call statistics recorded by the client for binary calls.
+/// Protocol control ops (handshake, cancellation, stream credits and
+/// the like) are not recorded.
+#[derive(Default)]
+pub(crate) struct RpcStats {
+    ops : Mutex<AHashMap<u32, RpcOpStats>>,
+}
+
+impl RpcStats {
+    fn record(&self, op : u32, f : impl FnOnce(&mut RpcOpStats)) {
+        if CtlOp::try_from(op).is_err() {
+            f(self.ops.lock().unwrap().entry(op).or_default());
+        }
+    }
+
+    pub fn sent(&self, op : u32, bytes : usize) {
+        self.record(op, |stats| {
+            stats.calls += 1;
+            stats.bytes_sent += bytes as u64;
+        });
+    }
+
+    /// Count bytes sent for a call after its initial request.
+    pub fn sent_bytes(&self, op : u32, bytes : usize) {
+        self.record(op, |stats| stats.bytes_sent += bytes as u64);
+    }
+
+    pub fn received(&self, op : u32, elapsed : Duration, bytes : usize, success : bool) {
+        self.record(op, |stats| {
+            stats.bytes_received += bytes as u64;
+            stats.latency.record(elapsed);
+            if success {
+                stats.successes += 1;
+            } else {
+                stats.errors += 1;
+            }
+        });
+    }
+
+    pub fn timeout(&self, op : u32) {
+        self.record(op, |stats| stats.timeouts += 1);
+    }
+
+    pub fn cancelled(&self, op : u32) {
+        self.record(op, |stats| stats.cancelled += 1);
+    }
+
+    pub fn snapshot(&self) -> AHashMap<u32, RpcOpStats> {
+        self.ops.lock().unwrap().clone()
+    }
+
+    pub fn reset(&self) {
+        self.ops.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_ops_are_not_recorded() {
+        let stats = RpcStats::default();
+        stats.sent(CtlOp::Handshake as u32, 16);
+        stats.sent(CtlOp::Cancel as u32, 8);
+        stats.cancelled(CtlOp::StreamAck as u32);
+        stats.sent(1, 32);
+        stats.received(1, Duration::from_millis(1), 4, true);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[&1].calls, 1);
+        assert_eq!(snapshot[&1].bytes_sent, 32);
+        assert_eq!(snapshot[&1].successes, 1);
+    }
+}
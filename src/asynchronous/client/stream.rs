@@ -0,0 +1,109 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::sync::atomic::Ordering;
+use borsh::{BorshSerialize,BorshDeserialize};
+use futures::stream::Stream;
+use workflow_core::channel::Receiver;
+use super::*;
+use super::error::Error;
+use super::result::Result;
+
+/// Stream of responses produced by a streaming op. Consumed items
+/// are acknowledged to the server in batches of half the stream
+/// window, so the server never runs more than one window ahead of
+/// the consumer. Dropping the stream before it ends cancels the
+/// request on the server.
+///
+/// Streams are not subject to call timeouts, as items may legitimately
+/// be far apart; apply a timeout to individual items where needed.
+pub struct RpcResponseStream<Resp> {
+    inner : Arc<Inner>,
+    id : u64,
+    receiver : Pin<Box<Receiver<Result<Vec<u8>>>>>,
+    window : u32,
+    consumed : u32,
+    decode : fn(Vec<u8>) -> Result<Resp>,
+}
+
+impl<Resp> RpcResponseStream<Resp> {
+    /// Request id identifying this stream.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl<Resp> Stream for RpcResponseStream<Resp> {
+    type Item = Result<Resp>;
+
+    fn poll_next(mut self : Pin<&mut Self>, cx : &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.receiver.as_mut().poll_next(cx) {
+            Poll::Ready(Some(item)) => {
+                self.consumed += 1;
+                if self.consumed >= (self.window / 2).max(1) {
+                    self.inner.stream_ack(self.id, self.consumed);
+                    self.consumed = 0;
+                }
+                Poll::Ready(Some(item.and_then(self.decode)))
+            },
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<Resp> Drop for RpcResponseStream<Resp> {
+    fn drop(&mut self) {
+        self.inner.cancel(self.id);
+    }
+}
+
+impl<Ops> RpcClient<Ops>
+where
+    Ops : Into<u32> + Send + Sync + 'static
+{
+    /// Number of items the server may send ahead of the consumer
+    /// for streams opened after this call.
+    pub fn set_stream_window(&self, window : u32) {
+        self.inner.stream_window.store(window, Ordering::SeqCst);
+    }
+
+    /// Open a stream for `op` yielding raw response data.
+    pub async fn stream_with_buffer(
+        &self,
+        op : Ops,
+        message : Message<'_>,
+    ) -> Result<RpcResponseStream<Vec<u8>>> {
+        let (id, window, receiver) = self.inner.open_stream(op.into(), message).await?;
+        Ok(RpcResponseStream {
+            inner : self.inner.clone(),
+            id,
+            receiver : Box::pin(receiver),
+            window,
+            consumed : 0,
+            decode : Ok,
+        })
+    }
+
+    /// Open a stream for `op` yielding deserialized responses.
+    pub async fn stream<Req, Resp>(
+        &self,
+        op : Ops,
+        req : Req,
+    ) -> Result<RpcResponseStream<Resp>>
+    where
+        Req : BorshSerialize + Send + Sync + 'static,
+        Resp : BorshDeserialize + Send + Sync + 'static,
+    {
+        let data = req.try_to_vec().map_err(|_| { Error::BorshSerialize })?;
+        let (id, window, receiver) = self.inner.open_stream(op.into(), Message::Request(&data)).await?;
+        Ok(RpcResponseStream {
+            inner : self.inner.clone(),
+            id,
+            receiver : Box::pin(receiver),
+            window,
+            consumed : 0,
+            decode : |data| Resp::try_from_slice(&data).map_err(|e| Error::BorshDeserialize(e.to_string())),
+        })
+    }
+}
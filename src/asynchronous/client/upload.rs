@@ -0,0 +1,44 @@
+use borsh::{BorshSerialize,BorshDeserialize};
+use futures::stream::Stream;
+use super::*;
+use super::error::Error;
+use super::result::Result;
+
+impl<Ops> RpcClient<Ops>
+where
+    Ops : Into<u32> + Send + Sync + 'static
+{
+    /// Upload a sequence of chunks to the handler of `op` together with
+    /// raw request data, returning the raw response. Chunks are sent as
+    /// the server grants credits for them; the server limits the total
+    /// size of an upload.
+    pub async fn upload_with_buffer<S>(
+        &self,
+        op : Ops,
+        message : Message<'_>,
+        chunks : S,
+    ) -> Result<Vec<u8>>
+    where
+        S : Stream<Item = Vec<u8>> + Unpin
+    {
+        self.inner.upload_with_buffer(op.into(), message, chunks).await
+    }
+
+    /// Upload a sequence of chunks to the handler of `op` together
+    /// with a typed request, returning the deserialized response.
+    pub async fn upload<Req, Resp, S>(
+        &self,
+        op : Ops,
+        req : Req,
+        chunks : S,
+    ) -> Result<Resp>
+    where
+        Req : BorshSerialize + Send + Sync + 'static,
+        Resp : BorshDeserialize + Send + Sync + 'static,
+        S : Stream<Item = Vec<u8>> + Unpin
+    {
+        let data = req.try_to_vec().map_err(|_| { Error::BorshSerialize })?;
+        let resp = self.upload_with_buffer(op, Message::Request(&data), chunks).await?;
+        Ok(Resp::try_from_slice(&resp).map_err(|e|Error::BorshDeserialize(e.to_string()))?)
+    }
+}
@@ -1,75 +1,58 @@
-use std::sync::Arc;
-use serde::{Serialize,Deserialize,de::DeserializeOwned};
-use workflow_log::log_trace;
+use serde::{Serialize,de::DeserializeOwned};
 use super::*;
-use super::error::*;
 use super::result::Result;
-
-#[derive(Clone)]
-pub struct RpcClientSerde 
+use workflow_core::time::Duration;
+
+/// JSON-RPC 2.0 client mode. Requests are posted as text frames
+/// using string method names and share the pending call map and
+/// timeouts with binary calls issued by the same client.
+impl<Ops> RpcClient<Ops>
+where
+    Ops : Into<u32> + Send + Sync + 'static
 {
-    rpc : RpcClient,
-}
-
-impl RpcClientSerde
-{
-    pub fn new(url : &str) -> Result<Arc<Self>> {
-        let rpc = RpcClient::new(url)?;
-        Ok(Arc::new(Self {
-            rpc,
-        }))
-    }
-
-    pub async fn connect(&self, block_until_connected:bool) -> Result<()> {
-        self.rpc.connect(block_until_connected).await
+    /// Issue a JSON-RPC request and deserialize its result. A JSON-RPC
+    /// error object is reported as [`Error::JsonRpc`](super::error::Error::JsonRpc).
+    pub async fn call_json<Req, Resp>(
+        &self,
+        method : &str,
+        params : Req,
+    ) -> Result<Resp>
+    where
+        Req : Serialize + Send + Sync + 'static,
+        Resp : DeserializeOwned + Send + Sync + 'static,
+    {
+        let params = serde_json::to_value(params)?;
+        let resp = self.inner.call_json_with_value(method, params, None).await?;
+        Ok(serde_json::from_slice(&resp)?)
     }
 
-    // pub async fn dispatch<'resp, Req, Resp>(
-    pub async fn dispatch<Req, Resp>(
-        self : &Arc<Self>,
-        req : Req,
-        callback : Arc<Box<(dyn Fn(Result<Resp>) + Sync + Send)>>
-    ) -> Result<()> 
+    /// Issue a JSON-RPC request that fails with [`Error::Timeout`](super::error::Error::Timeout)
+    /// once `timeout` elapses, overriding the client timeout.
+    pub async fn call_json_with_timeout<Req, Resp>(
+        &self,
+        method : &str,
+        params : Req,
+        timeout : Duration,
+    ) -> Result<Resp>
     where
         Req : Serialize + Send + Sync + 'static,
-        // Resp : Deserialize<'resp> + Send + Sync + 'static,
         Resp : DeserializeOwned + Send + Sync + 'static,
     {
-
-        let json = serde_json::to_string(&req)?;
-        //.map_err(|e:serde_json::Error|e.into())?; 
-
-        self.rpc.call(RpcOps::Serde as u32, Message::Request(&json.as_bytes()), Arc::new(Box::new(move |result| {
-            log_trace!("* * * * * Got response: {:?}", result);
-
-            match result {
-                Ok(data) => {
-                    match data {
-                        Some(data) => {
-                            match String::from_utf8(data.to_vec()) {
-                                Ok(utf8) => {
-                                    // TODO use Deserialize instead of DeserializeOwned
-                                    match serde_json::from_str(&utf8) {
-                                            Ok(resp) => { callback(Ok(resp)) },
-                                            Err(e) => callback(Err(e.into())),
-                                    }
-                                },
-                                Err(e) => {
-                                    callback(Err(e.utf8_error().into()));
-                                }
-                            }
-                        },
-                        None => {
-                            callback(Err(Error::NoDataInSuccessResponse));
-                        }
-                    }
-                },
-                Err(err) => {
-                    callback(Err(err));
-                }
-            }
-        }))).await?;
-        Ok(())
+        let params = serde_json::to_value(params)?;
+        let resp = self.inner.call_json_with_value(method, params, Some(timeout)).await?;
+        Ok(serde_json::from_slice(&resp)?)
     }
 
+    /// Post a JSON-RPC notification; the server produces no response.
+    pub async fn notify_json<Req>(
+        &self,
+        method : &str,
+        params : Req,
+    ) -> Result<()>
+    where
+        Req : Serialize + Send + Sync + 'static,
+    {
+        let params = serde_json::to_value(params)?;
+        self.inner.notify_json_with_value(method, params).await
+    }
 }
@@ -11,6 +11,32 @@ pub enum RpcResponseError {
     RespSerialize,
     Data(Vec<u8>),
     Text(String),
+    // variants are encoded by position; new
+    // variants must only be appended
+    HandshakeRequired,
+    NotFound,
+    Forbidden,
+    PayloadTooLarge,
+}
+
+impl RpcResponseError {
+    /// Name of the error variant, used as a metrics key.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RpcResponseError::NoData => "NoData",
+            RpcResponseError::PoisonError => "PoisonError",
+            RpcResponseError::NonBorshRequest => "NonBorshRequest",
+            RpcResponseError::NonSerdeRequest => "NonSerdeRequest",
+            RpcResponseError::ReqDeserialize => "ReqDeserialize",
+            RpcResponseError::HandshakeRequired => "HandshakeRequired",
+            RpcResponseError::NotFound => "NotFound",
+            RpcResponseError::Forbidden => "Forbidden",
+            RpcResponseError::PayloadTooLarge => "PayloadTooLarge",
+            RpcResponseError::RespSerialize => "RespSerialize",
+            RpcResponseError::Data(_) => "Data",
+            RpcResponseError::Text(_) => "Text",
+        }
+    }
 }
 
 impl From<std::io::Error> for RpcResponseError {
@@ -24,3 +50,34 @@ impl<T> From<PoisonError<T>> for RpcResponseError {
         RpcResponseError::PoisonError
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn discriminant(err : RpcResponseError) -> u8 {
+        err.try_to_vec().unwrap()[0]
+    }
+
+    #[test]
+    fn wire_discriminants_are_stable() {
+        assert_eq!(discriminant(RpcResponseError::NoData), 0);
+        assert_eq!(discriminant(RpcResponseError::PoisonError), 1);
+        assert_eq!(discriminant(RpcResponseError::NonBorshRequest), 2);
+        assert_eq!(discriminant(RpcResponseError::NonSerdeRequest), 3);
+        assert_eq!(discriminant(RpcResponseError::ReqDeserialize), 4);
+        assert_eq!(discriminant(RpcResponseError::RespSerialize), 5);
+        assert_eq!(discriminant(RpcResponseError::Data(vec![])), 6);
+        assert_eq!(discriminant(RpcResponseError::Text(String::new())), 7);
+        assert_eq!(discriminant(RpcResponseError::HandshakeRequired), 8);
+        assert_eq!(discriminant(RpcResponseError::NotFound), 9);
+        assert_eq!(discriminant(RpcResponseError::Forbidden), 10);
+        assert_eq!(discriminant(RpcResponseError::PayloadTooLarge), 11);
+    }
+
+    #[test]
+    fn handshake_required_round_trip() {
+        let data = RpcResponseError::HandshakeRequired.try_to_vec().unwrap();
+        assert!(matches!(RpcResponseError::try_from_slice(&data).unwrap(), RpcResponseError::HandshakeRequired));
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets in microseconds.
+/// Samples above the last bound are counted in an overflow bucket.
+pub const LATENCY_BUCKETS_US: [u64; 12] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000
+];
+
+/// Fixed-bucket latency histogram.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    /// Sample counts per bucket of [`LATENCY_BUCKETS_US`] followed by the overflow bucket
+    pub buckets : [u64; 13],
+    pub count : u64,
+    pub sum : Duration,
+    pub max : Duration,
+}
+
+impl Histogram {
+    pub fn record(&mut self, sample : Duration) {
+        let us = sample.as_micros() as u64;
+        let index = LATENCY_BUCKETS_US.iter().position(|bound| us <= *bound).unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(sample);
+        if sample > self.max {
+            self.max = sample;
+        }
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            None
+        } else {
+            let mean = self.sum.as_nanos() / self.count as u128;
+            Some(Duration::new((mean / 1_000_000_000) as u64, (mean % 1_000_000_000) as u32))
+        }
+    }
+
+    /// Upper bound of the bucket containing the given percentile
+    /// (`0.0..=1.0`). Samples in the overflow bucket report the
+    /// maximum observed latency.
+    pub fn percentile(&self, percentile : f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let target = ((self.count as f64) * percentile.clamp(0.0, 1.0)).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return match LATENCY_BUCKETS_US.get(index) {
+                    Some(bound) => Some(Duration::from_micros(*bound).min(self.max)),
+                    None => Some(self.max),
+                };
+            }
+        }
+        Some(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mean_beyond_u32_samples() {
+        let histogram = Histogram {
+            count : u32::MAX as u64 + 1,
+            sum : Duration::from_micros(u32::MAX as u64 + 1),
+            ..Default::default()
+        };
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(1)));
+    }
+
+    #[test]
+    fn mean_and_percentiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.mean(), None);
+        for us in [100, 200, 300, 2_000_000] {
+            histogram.record(Duration::from_micros(us));
+        }
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(500_150)));
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_micros(250)));
+        assert_eq!(histogram.percentile(1.0), Some(Duration::from_micros(2_000_000)));
+    }
+}
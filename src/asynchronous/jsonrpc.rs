@@ -0,0 +1,118 @@
+use serde::{Serialize,Deserialize};
+use serde_json::Value;
+
+pub const JSONRPC_VERSION: &str = "2.0";
+/// Method authenticating a connection that has an authenticator
+/// installed. Its params carry a [`HandshakeRequest`](super::auth::HandshakeRequest)
+/// and its result a [`HandshakeResponse`](super::auth::HandshakeResponse).
+pub const JSONRPC_HANDSHAKE_METHOD: &str = "rpc.handshake";
+
+/// JSON-RPC 2.0 error object.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonRpcError {
+    pub code : i64,
+    pub message : String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data : Option<Value>,
+}
+
+impl JsonRpcError {
+    pub const PARSE_ERROR: i64 = -32700;
+    pub const INVALID_REQUEST: i64 = -32600;
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_PARAMS: i64 = -32602;
+    pub const INTERNAL_ERROR: i64 = -32603;
+    /// Server-defined: the connection has not completed the handshake
+    pub const UNAUTHORIZED: i64 = -32001;
+
+    pub fn new(code : i64, message : &str) -> Self {
+        JsonRpcError {
+            code,
+            message : message.to_string(),
+            data : None,
+        }
+    }
+
+    pub fn with_data(mut self, data : Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn parse_error() -> Self {
+        Self::new(Self::PARSE_ERROR, "Parse error")
+    }
+
+    pub fn invalid_request() -> Self {
+        Self::new(Self::INVALID_REQUEST, "Invalid Request")
+    }
+
+    pub fn method_not_found(method : &str) -> Self {
+        Self::new(Self::METHOD_NOT_FOUND, "Method not found").with_data(Value::String(method.to_string()))
+    }
+
+    pub fn invalid_params(reason : &str) -> Self {
+        Self::new(Self::INVALID_PARAMS, "Invalid params").with_data(Value::String(reason.to_string()))
+    }
+
+    pub fn internal_error(reason : &str) -> Self {
+        Self::new(Self::INTERNAL_ERROR, "Internal error").with_data(Value::String(reason.to_string()))
+    }
+
+    pub fn unauthorized() -> Self {
+        Self::new(Self::UNAUTHORIZED, "Unauthorized")
+    }
+}
+
+/// JSON-RPC 2.0 request object. A request without an `id`
+/// is a notification and produces no response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonRpcRequest {
+    pub jsonrpc : String,
+    pub method : String,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub params : Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id : Option<Value>,
+}
+
+impl JsonRpcRequest {
+    pub fn new(method : &str, params : Value, id : Option<Value>) -> Self {
+        JsonRpcRequest {
+            jsonrpc : JSONRPC_VERSION.to_string(),
+            method : method.to_string(),
+            params,
+            id,
+        }
+    }
+}
+
+/// JSON-RPC 2.0 response object.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonRpcResponse {
+    pub jsonrpc : String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result : Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error : Option<JsonRpcError>,
+    pub id : Value,
+}
+
+impl JsonRpcResponse {
+    pub fn result(id : Value, result : Value) -> Self {
+        JsonRpcResponse {
+            jsonrpc : JSONRPC_VERSION.to_string(),
+            result : Some(result),
+            error : None,
+            id,
+        }
+    }
+
+    pub fn error(id : Value, error : JsonRpcError) -> Self {
+        JsonRpcResponse {
+            jsonrpc : JSONRPC_VERSION.to_string(),
+            result : None,
+            error : Some(error),
+            id,
+        }
+    }
+}
@@ -10,7 +10,7 @@ pub enum Message<'data> {
 }
 
 impl<'data> Message<'data> {
-    fn data(&self) -> &'data [u8] {
+    pub fn data(&self) -> &'data [u8] {
         match self {
             Message::Request(data) => data,
             Message::Post(data) => data,
@@ -19,6 +19,11 @@ impl<'data> Message<'data> {
 }
 
 pub fn to_ws_msg(msg : (ReqHeader, Message<'_>)) -> WebSocketMessage {
+    to_vec(msg).into()
+}
+
+/// Serialize a request header and its payload into a single frame buffer.
+pub fn to_vec(msg : (ReqHeader, Message<'_>)) -> Vec<u8> {
     let (header, message) = msg;
     let data = message.data();
     let len = data.len() + size_of::<ReqHeader>();
@@ -27,7 +32,7 @@ pub fn to_ws_msg(msg : (ReqHeader, Message<'_>)) -> WebSocketMessage {
     let dest_header: &mut ReqHeader = unsafe { std::mem::transmute(&mut buffer[0]) };
     *dest_header = header;
     buffer[size_of::<ReqHeader>()..].copy_from_slice(data);
-    buffer.into()
+    buffer
 }
 
 #[derive(Clone, Copy)]
@@ -48,6 +53,57 @@ u32_try_from! {
     pub enum RespStatus {
         Success = 0,
         Error = 1,
+        /// Unsolicited server-to-client message; the `id` field
+        /// of the [`RespHeader`] carries the notification op.
+        Notification = 2,
+        /// Request rejected because the connection has not
+        /// completed the authentication handshake.
+        Unauthorized = 3,
+        /// Request frame is too short to carry a complete
+        /// [`ReqHeader`]; only the request id could be recovered.
+        MalformedHeader = 4,
+        /// Request op is not recognized by the server.
+        UnknownOp = 5,
+        /// Stream item; more frames follow for the same request id.
+        StreamItem = 6,
+        /// Stream completed; no more frames follow for the request id.
+        StreamEnd = 7,
+        /// Grants the client additional upload chunks; the payload
+        /// carries the number of chunks as a borsh `u32`.
+        UploadAck = 8,
+        /// Message published to a topic the client has subscribed to;
+        /// the payload carries a [`Publication`](super::pubsub::Publication).
+        Publish = 9,
+    }
+}
+
+u32_try_from! {
+    /// Reserved ops carried in the [`ReqHeader`] for protocol-level
+    /// control frames. These lie outside of the range used by
+    /// application ops.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum CtlOp {
+        Handshake = 0xffff_ff00,
+        /// Abort the request whose id is carried in the header.
+        /// The server produces no response for this frame.
+        Cancel = 0xffff_ff01,
+        /// Open a response stream; the payload carries a
+        /// [`StreamRequest`](super::stream::StreamRequest).
+        Stream = 0xffff_ff02,
+        /// Grant the server additional stream items; the payload
+        /// carries the number of items as a borsh `u32`.
+        StreamAck = 0xffff_ff03,
+        /// Open an upload; the payload carries an
+        /// [`UploadRequest`](super::stream::UploadRequest).
+        Upload = 0xffff_ff04,
+        /// Upload chunk for the upload opened with the same request id.
+        UploadChunk = 0xffff_ff05,
+        /// No more chunks follow for the upload.
+        UploadEnd = 0xffff_ff06,
+        /// Subscribe the connection to the topic named in the payload.
+        Subscribe = 0xffff_ff07,
+        /// Unsubscribe the connection from the topic named in the payload.
+        Unsubscribe = 0xffff_ff08,
     }
 }
 
@@ -169,3 +225,53 @@ impl<'data> TryFrom<&'data [u8]> for RespMessage<'data> {
         Ok(message)
     }
 }
+
+/// Serialize a server-to-client notification frame. Notifications
+/// reuse the [`RespHeader`] layout with `status` set to
+/// [`RespStatus::Notification`] and `id` carrying the op.
+pub fn notification_to_vec(op : u32, data : &[u8]) -> Result<Vec<u8>, Error> {
+    RespMessage::new(op as u64, RespStatus::Notification as u32, data).try_to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_error_statuses_are_stable() {
+        assert_eq!(RespStatus::Success as u32, 0);
+        assert_eq!(RespStatus::Error as u32, 1);
+        assert_eq!(RespStatus::Notification as u32, 2);
+        assert_eq!(RespStatus::Unauthorized as u32, 3);
+        assert_eq!(RespStatus::MalformedHeader as u32, 4);
+        assert_eq!(RespStatus::UnknownOp as u32, 5);
+        assert!(matches!(RespStatus::try_from(4), Ok(RespStatus::MalformedHeader)));
+        assert!(matches!(RespStatus::try_from(5), Ok(RespStatus::UnknownOp)));
+    }
+
+    #[test]
+    fn short_request_frame_is_rejected() {
+        let frame = vec![0u8; size_of::<ReqHeader>() - 1];
+        assert!(matches!(ReqMessage::try_from(&frame[..]), Err(Error::HeaderSize)));
+    }
+
+    #[test]
+    fn request_frame_round_trip() {
+        let frame = to_vec((ReqHeader { id : 0x0102_0304_0506_0708, op : 7 }, Message::Request(&[1, 2, 3])));
+        assert_eq!(frame.len(), size_of::<ReqHeader>() + 3);
+        let req = ReqMessage::try_from(&frame[..]).unwrap();
+        assert_eq!(req.id, 0x0102_0304_0506_0708);
+        assert_eq!(req.op, 7);
+        assert_eq!(req.data, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn error_status_response_round_trip() {
+        let frame = RespMessage::new(42, RespStatus::UnknownOp as u32, &[]).try_to_vec().unwrap();
+        assert_eq!(frame.len(), size_of::<RespHeader>());
+        let resp = RespMessage::try_from(&frame[..]).unwrap();
+        assert_eq!(resp.id, 42);
+        assert_eq!(resp.status, RespStatus::UnknownOp as u32);
+        assert!(resp.data.is_empty());
+    }
+}
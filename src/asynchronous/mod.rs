@@ -3,6 +3,11 @@ pub mod message;
 pub mod error;
 pub mod result;
 pub mod ops;
+pub mod auth;
+pub mod jsonrpc;
+pub mod histogram;
+pub mod stream;
+pub mod pubsub;
 
 #[cfg(not(any(target_arch = "wasm32", target_os = "solana")))]
 pub mod server;
\ No newline at end of file
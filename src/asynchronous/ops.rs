@@ -11,3 +11,16 @@ u32_try_from!{
     }
 }
 
+
+/// Error returned when a `u32` does not name any op of an
+/// enum generated by [`rpc`](crate::rpc).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownOpError(pub u32);
+
+impl std::fmt::Display for UnknownOpError {
+    fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown op {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownOpError { }
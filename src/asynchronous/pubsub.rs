@@ -0,0 +1,19 @@
+//! Topic subscriptions. Topics are identified by name; parameterized
+//! topics carry their parameters in the name, e.g. `"ticker/BTC"`.
+//! Subscribe and unsubscribe requests carry the topic name as a borsh
+//! `String` in the payload of a [`CtlOp::Subscribe`](super::message::CtlOp::Subscribe)
+//! or [`CtlOp::Unsubscribe`](super::message::CtlOp::Unsubscribe) request.
+
+use borsh::{BorshSerialize,BorshDeserialize};
+
+/// Number of publications buffered for each client subscriber
+/// unless configured otherwise. Publications received while the
+/// buffer is full are dropped.
+pub const DEFAULT_SUBSCRIPTION_CAPACITY : usize = 256;
+
+/// Payload of a [`RespStatus::Publish`](super::message::RespStatus::Publish) frame.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct Publication {
+    pub topic : String,
+    pub data : Vec<u8>,
+}
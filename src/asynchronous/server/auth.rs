@@ -0,0 +1,22 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use crate::asynchronous::auth::RpcIdentity;
+use crate::asynchronous::error::RpcResponseError;
+use super::context::RpcContext;
+
+pub use crate::asynchronous::auth::AuthCredentials;
+
+/// Pluggable credential validation used by the server during the
+/// handshake. When an authenticator is installed, requests received
+/// before a successful handshake are rejected with
+/// [`RespStatus::Unauthorized`](crate::asynchronous::message::RespStatus).
+#[async_trait]
+pub trait RpcAuthenticator : Send + Sync + 'static {
+    /// Produce a challenge for challenge-response authentication.
+    async fn challenge(self : Arc<Self>, _ctx : Arc<RpcContext>) -> Result<Vec<u8>, RpcResponseError> {
+        Err(RpcResponseError::Text("challenge-response authentication is not supported".to_string()))
+    }
+
+    /// Validate credentials and produce the identity attached to the connection.
+    async fn authenticate(self : Arc<Self>, ctx : Arc<RpcContext>, credentials : AuthCredentials) -> Result<RpcIdentity, RpcResponseError>;
+}
@@ -0,0 +1,213 @@
+use std::sync::{Arc, Mutex};
+use ahash::AHashMap;
+use borsh::BorshSerialize;
+use workflow_log::log_trace;
+use crate::asynchronous::message::*;
+use super::context::RpcContext;
+use super::pubsub::RpcTopics;
+use super::error::Error;
+use super::result::Result;
+
+/// Registry of active connections keyed by connection id. Connections
+/// are registered as soon as they are accepted and every registered
+/// connection can be posted to, broadcast to and disconnected. Frames
+/// posted before the peer has sent anything are queued by the context
+/// and delivered in order once the WebSocket server hands over the
+/// connection sink with the peer's first frame.
+pub struct RpcConnections {
+    connections : Mutex<AHashMap<u64, Arc<RpcContext>>>,
+    topics : Arc<RpcTopics>,
+}
+
+impl RpcConnections {
+    pub(crate) fn new(topics : Arc<RpcTopics>) -> Self {
+        RpcConnections {
+            connections : Mutex::new(AHashMap::new()),
+            topics,
+        }
+    }
+
+    pub(crate) fn insert(&self, ctx : &Arc<RpcContext>) {
+        self.connections.lock().unwrap().insert(ctx.id, ctx.clone());
+    }
+
+    pub(crate) fn remove(&self, id : u64) {
+        self.connections.lock().unwrap().remove(&id);
+    }
+
+    pub fn get(&self, id : u64) -> Option<Arc<RpcContext>> {
+        self.connections.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Arc<RpcContext>> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.lock().unwrap().is_empty()
+    }
+
+    /// Post a raw notification for `op` to the connection `id`.
+    pub fn send_with_buffer(&self, id : u64, op : u32, data : &[u8]) -> Result<()> {
+        let ctx = self.get(id).ok_or(Error::ConnectionNotFound(id))?;
+        ctx.notify_with_buffer(op, data)
+    }
+
+    /// Serialize `msg` and post it as a notification for `op` to the connection `id`.
+    pub fn send<Op, Msg>(&self, id : u64, op : Op, msg : &Msg) -> Result<()>
+    where
+        Op : Into<u32>,
+        Msg : BorshSerialize,
+    {
+        let data = msg.try_to_vec().map_err(|_| Error::BorshSerialize)?;
+        self.send_with_buffer(id, op.into(), &data)
+    }
+
+    /// Post a raw notification for `op` to every connection accepted
+    /// by `filter`. The frame is serialized once and shared by all
+    /// recipients. Returns the number of connections it was posted to.
+    pub fn broadcast_filtered_with_buffer<F>(&self, op : u32, data : &[u8], filter : F) -> Result<usize>
+    where
+        F : Fn(&RpcContext) -> bool
+    {
+        let frame = notification_to_vec(op, data).map_err(|_| Error::BorshSerialize)?;
+        let mut delivered = 0;
+        for ctx in self.list() {
+            if !filter(&ctx) {
+                continue;
+            }
+            match ctx.post(frame.clone()) {
+                Ok(_) => { delivered += 1; },
+                Err(err) => { log_trace!("RPC unable to broadcast to {}: {}", ctx.peer, err); }
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Post a raw notification for `op` to every connection.
+    pub fn broadcast_with_buffer(&self, op : u32, data : &[u8]) -> Result<usize> {
+        self.broadcast_filtered_with_buffer(op, data, |_| true)
+    }
+
+    /// Serialize `msg` and post it as a notification for `op`
+    /// to every connection accepted by `filter`.
+    pub fn broadcast_filtered<Op, Msg, F>(&self, op : Op, msg : &Msg, filter : F) -> Result<usize>
+    where
+        Op : Into<u32>,
+        Msg : BorshSerialize,
+        F : Fn(&RpcContext) -> bool
+    {
+        let data = msg.try_to_vec().map_err(|_| Error::BorshSerialize)?;
+        self.broadcast_filtered_with_buffer(op.into(), &data, filter)
+    }
+
+    /// Serialize `msg` and post it as a notification for `op` to every connection.
+    pub fn broadcast<Op, Msg>(&self, op : Op, msg : &Msg) -> Result<usize>
+    where
+        Op : Into<u32>,
+        Msg : BorshSerialize,
+    {
+        self.broadcast_filtered(op, msg, |_| true)
+    }
+
+    /// Close the connection `id`. The connection is removed from the
+    /// registry and from its topics, its requests, streams and uploads
+    /// are aborted and the close frame is sent to the peer right away.
+    /// Frames received from the peer afterwards are not processed.
+    /// A peer that has not sent anything yet receives the close frame
+    /// once the WebSocket server hands over its sink.
+    pub fn disconnect(&self, id : u64, reason : &str) -> Result<()> {
+        let ctx = self.connections.lock().unwrap().remove(&id).ok_or(Error::ConnectionNotFound(id))?;
+        ctx.cancel_all_requests();
+        self.topics.remove_connection(&ctx);
+        ctx.close(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+    use futures::future::{AbortHandle, Abortable};
+
+    /// Connection whose frames are collected by the returned receiver.
+    fn connect(connections : &RpcConnections) -> (Arc<RpcContext>, UnboundedReceiver<tungstenite::Message>) {
+        let ctx = Arc::new(RpcContext::new("127.0.0.1:1".parse().unwrap(), 1));
+        let (sink, frames) = unbounded_channel();
+        ctx.bind_sink(&sink);
+        connections.insert(&ctx);
+        (ctx, frames)
+    }
+
+    async fn recv(frames : &mut UnboundedReceiver<tungstenite::Message>) -> tungstenite::Message {
+        tokio::time::timeout(Duration::from_secs(5), frames.recv()).await
+            .expect("no frame received")
+            .expect("connection sink closed")
+    }
+
+    async fn recv_notification(frames : &mut UnboundedReceiver<tungstenite::Message>) -> (u64, Vec<u8>) {
+        let data = recv(frames).await.into_data();
+        let msg = RespMessage::try_from(&data[..]).unwrap();
+        assert_eq!(msg.status, RespStatus::Notification as u32);
+        (msg.id, msg.data.to_vec())
+    }
+
+    #[tokio::test]
+    async fn notifications_reach_the_addressed_connection() {
+        let connections = RpcConnections::new(Arc::new(RpcTopics::default()));
+        let (first, mut first_frames) = connect(&connections);
+        let (second, mut second_frames) = connect(&connections);
+
+        connections.send(first.id, 7u32, &1u8).unwrap();
+        connections.send(second.id, 7u32, &2u8).unwrap();
+        assert_eq!(recv_notification(&mut first_frames).await, (7, vec![1]));
+        assert_eq!(recv_notification(&mut second_frames).await, (7, vec![2]));
+
+        assert!(matches!(connections.send(0, 7u32, &1u8), Err(Error::ConnectionNotFound(0))));
+    }
+
+    #[tokio::test]
+    async fn broadcasts_reach_the_selected_connections() {
+        let connections = RpcConnections::new(Arc::new(RpcTopics::default()));
+        let (first, mut first_frames) = connect(&connections);
+        let (_second, mut second_frames) = connect(&connections);
+
+        assert_eq!(connections.broadcast(7u32, &1u8).unwrap(), 2);
+        assert_eq!(recv_notification(&mut first_frames).await, (7, vec![1]));
+        assert_eq!(recv_notification(&mut second_frames).await, (7, vec![1]));
+
+        assert_eq!(connections.broadcast_filtered(7u32, &2u8, |ctx| ctx.id != first.id).unwrap(), 1);
+        assert_eq!(connections.broadcast_with_buffer(7, &[3]).unwrap(), 2);
+        assert_eq!(recv_notification(&mut first_frames).await, (7, vec![3]));
+        assert_eq!(recv_notification(&mut second_frames).await, (7, vec![2]));
+        assert_eq!(recv_notification(&mut second_frames).await, (7, vec![3]));
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_the_connection_right_away() {
+        let topics = Arc::new(RpcTopics::default());
+        let connections = RpcConnections::new(topics.clone());
+        let (ctx, mut frames) = connect(&connections);
+        topics.subscribe("topic", &ctx);
+        let (handle, registration) = AbortHandle::new_pair();
+        ctx.register_request(1, handle);
+        let request = tokio::spawn(Abortable::new(futures::future::pending::<()>(), registration));
+
+        connections.disconnect(ctx.id, "bye").unwrap();
+        assert!(connections.is_empty());
+        assert_eq!(topics.subscribers("topic"), 0);
+        assert!(ctx.is_closed());
+        assert!(request.await.unwrap().is_err());
+        assert!(matches!(recv(&mut frames).await, tungstenite::Message::Close(Some(frame)) if frame.reason == "bye"));
+
+        // frames posted after the close frame are not sent
+        let _ = ctx.notify_with_buffer(7, &[]);
+        assert!(tokio::time::timeout(Duration::from_millis(50), frames.recv()).await.map(|frame| frame.is_none()).unwrap_or(true));
+        assert!(matches!(connections.disconnect(ctx.id, "bye"), Err(Error::ConnectionNotFound(_))));
+    }
+}
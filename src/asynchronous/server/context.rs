@@ -0,0 +1,329 @@
+use std::any::Any;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::SystemTime;
+use tungstenite::protocol::{CloseFrame, frame::coding::CloseCode};
+use ahash::{AHashMap, AHashSet};
+use futures::future::AbortHandle;
+use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+use borsh::BorshSerialize;
+use crate::asynchronous::message::*;
+use crate::asynchronous::auth::RpcIdentity;
+use crate::asynchronous::error::RpcResponseError;
+use super::error::Error;
+use super::result::Result;
+
+static CONNECTION_ID : AtomicU64 = AtomicU64::new(1);
+
+/// Point at which a request of the connection may start executing,
+/// obtained from [`RpcContext::turn`] in the order requests are read.
+pub(crate) enum Turn {
+    /// Once the request read before it has completed; `_done` is
+    /// dropped, releasing the next request, once this one completes
+    Sequential { previous : Option<oneshot::Receiver<()>>, _done : oneshot::Sender<()> },
+    /// Once a permit of the connection's in-flight limit is available
+    Concurrent { in_flight : Arc<Semaphore>, permit : Option<OwnedSemaphorePermit> },
+}
+
+impl Turn {
+    /// Wait until the request may execute. The request keeps its
+    /// turn until this is dropped, whether it completes or is aborted.
+    pub(crate) async fn wait(&mut self) {
+        match self {
+            Turn::Sequential { previous, .. } => {
+                if let Some(previous) = previous.take() {
+                    // nothing is ever sent; the previous turn ends when dropped
+                    let _ = previous.await;
+                }
+            },
+            Turn::Concurrent { in_flight, permit } => {
+                *permit = in_flight.clone().acquire_owned().await.ok();
+            }
+        }
+    }
+}
+
+struct Upload {
+    op : u32,
+    sender : tokio::sync::mpsc::Sender<Vec<u8>>,
+    received : usize,
+}
+
+/// Per-connection context created when a peer connects and
+/// supplied to every [`RpcHandler`](super::RpcHandler) call
+/// made on behalf of that connection.
+pub struct RpcContext {
+    /// Server-unique connection id
+    pub id : u64,
+    pub peer : SocketAddr,
+    /// Time at which the connection was accepted
+    pub connected : SystemTime,
+    /// Outbound frames, including responses. Created when the connection
+    /// is accepted and forwarded to the connection sink once the WebSocket
+    /// server supplies it. This is the only path to the peer, which keeps
+    /// all frames in the order they were posted.
+    sink : UnboundedSender<tungstenite::Message>,
+    outbound : Mutex<Option<UnboundedReceiver<tungstenite::Message>>>,
+    /// Set once `close()` has been called
+    closed : AtomicBool,
+    session : Mutex<Option<Arc<dyn Any + Send + Sync>>>,
+    identity : Mutex<Option<RpcIdentity>>,
+    challenge : Mutex<Option<Vec<u8>>>,
+    in_flight : Arc<Semaphore>,
+    /// End of the turn of the last sequential request read
+    sequence : Mutex<Option<oneshot::Receiver<()>>>,
+    requests : Mutex<AHashMap<u64, AbortHandle>>,
+    streams : Mutex<AHashMap<u64, Arc<Semaphore>>>,
+    uploads : Mutex<AHashMap<u64, Upload>>,
+    topics : Mutex<AHashSet<String>>,
+}
+
+impl RpcContext {
+    pub(crate) fn new(peer : SocketAddr, max_in_flight : usize) -> Self {
+        let (sink, outbound) = tokio::sync::mpsc::unbounded_channel();
+        RpcContext {
+            id : CONNECTION_ID.fetch_add(1, Ordering::SeqCst),
+            peer,
+            connected : SystemTime::now(),
+            sink,
+            outbound : Mutex::new(Some(outbound)),
+            closed : AtomicBool::new(false),
+            session : Mutex::new(None),
+            identity : Mutex::new(None),
+            challenge : Mutex::new(None),
+            in_flight : Arc::new(Semaphore::new(max_in_flight)),
+            sequence : Mutex::new(None),
+            requests : Mutex::new(AHashMap::new()),
+            streams : Mutex::new(AHashMap::new()),
+            uploads : Mutex::new(AHashMap::new()),
+            topics : Mutex::new(AHashSet::new()),
+        }
+    }
+
+    /// Take the turn of the request just read. Sequential requests run
+    /// one at a time in the order their turns were taken, others run
+    /// once fewer than `max_in_flight` requests are executing.
+    pub(crate) fn turn(&self, sequential : bool) -> Turn {
+        if !sequential {
+            return Turn::Concurrent { in_flight : self.in_flight.clone(), permit : None };
+        }
+
+        let (done, next) = oneshot::channel();
+        let previous = self.sequence.lock().unwrap().replace(next);
+        Turn::Sequential { previous, _done : done }
+    }
+
+    /// Topics this connection is subscribed to.
+    pub fn topics(&self) -> Vec<String> {
+        self.topics.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub(crate) fn add_topic(&self, topic : &str) -> bool {
+        self.topics.lock().unwrap().insert(topic.to_string())
+    }
+
+    pub(crate) fn remove_topic(&self, topic : &str) -> bool {
+        self.topics.lock().unwrap().remove(topic)
+    }
+
+    pub(crate) fn take_topics(&self) -> Vec<String> {
+        self.topics.lock().unwrap().drain().collect()
+    }
+
+    pub(crate) fn register_upload(&self, id : u64, op : u32, sender : tokio::sync::mpsc::Sender<Vec<u8>>) {
+        self.uploads.lock().unwrap().insert(id, Upload { op, sender, received : 0 });
+    }
+
+    /// Closes the chunk stream of the upload with the given id.
+    pub(crate) fn unregister_upload(&self, id : u64) {
+        self.uploads.lock().unwrap().remove(&id);
+    }
+
+    /// Pass a chunk to the handler processing the upload. Returns the
+    /// op of the upload, `None` if no such upload is open, or an error
+    /// if the upload has exceeded `max_size` or its chunk window.
+    pub(crate) fn push_upload_chunk(&self, id : u64, chunk : &[u8], max_size : usize) -> Option<std::result::Result<u32, RpcResponseError>> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let upload = uploads.get_mut(&id)?;
+        upload.received += chunk.len();
+        if upload.received > max_size {
+            uploads.remove(&id);
+            return Some(Err(RpcResponseError::PayloadTooLarge));
+        }
+
+        let op = upload.op;
+        match upload.sender.try_send(chunk.to_vec()) {
+            Ok(_) => Some(Ok(op)),
+            Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => {
+                // the handler no longer reads the upload
+                Some(Ok(op))
+            },
+            Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+                uploads.remove(&id);
+                Some(Err(RpcResponseError::Text("upload window exceeded".to_string())))
+            }
+        }
+    }
+
+    pub(crate) fn register_stream(&self, id : u64, credits : Arc<Semaphore>) {
+        self.streams.lock().unwrap().insert(id, credits);
+    }
+
+    pub(crate) fn unregister_stream(&self, id : u64) {
+        self.streams.lock().unwrap().remove(&id);
+    }
+
+    /// Allow the stream with the given id to send `credits` more items.
+    pub(crate) fn stream_ack(&self, id : u64, credits : u32) {
+        if let Some(semaphore) = self.streams.lock().unwrap().get(&id) {
+            semaphore.add_permits(credits as usize);
+        }
+    }
+
+    pub(crate) fn register_request(&self, id : u64, handle : AbortHandle) {
+        self.requests.lock().unwrap().insert(id, handle);
+    }
+
+    pub(crate) fn unregister_request(&self, id : u64) {
+        self.requests.lock().unwrap().remove(&id);
+    }
+
+    /// Abort the in-flight request with the given id. Returns `false`
+    /// if no such request is being processed.
+    pub(crate) fn cancel_request(&self, id : u64) -> bool {
+        match self.requests.lock().unwrap().remove(&id) {
+            Some(handle) => {
+                handle.abort();
+                true
+            },
+            None => false
+        }
+    }
+
+    pub(crate) fn cancel_all_requests(&self) {
+        for (_, handle) in self.requests.lock().unwrap().drain() {
+            handle.abort();
+        }
+        self.uploads.lock().unwrap().clear();
+    }
+
+    /// Identity attached by a successful authentication handshake.
+    pub fn identity(&self) -> Option<RpcIdentity> {
+        self.identity.lock().unwrap().clone()
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.identity.lock().unwrap().is_some()
+    }
+
+    pub(crate) fn set_identity(&self, identity : RpcIdentity) {
+        *self.identity.lock().unwrap() = Some(identity);
+    }
+
+    pub(crate) fn clear_identity(&self) {
+        self.identity.lock().unwrap().take();
+    }
+
+    pub(crate) fn set_challenge(&self, challenge : Vec<u8>) {
+        *self.challenge.lock().unwrap() = Some(challenge);
+    }
+
+    pub(crate) fn take_challenge(&self) -> Option<Vec<u8>> {
+        self.challenge.lock().unwrap().take()
+    }
+
+    /// Start forwarding frames posted through the context to the
+    /// connection sink. Frames posted before the sink is available are
+    /// delivered first, in the order they were posted.
+    pub(crate) fn bind_sink(&self, sink : &UnboundedSender<tungstenite::Message>) {
+        let outbound = self.outbound.lock().unwrap().take();
+        if let Some(mut outbound) = outbound {
+            let sink = sink.clone();
+            tokio::spawn(async move {
+                while let Some(msg) = outbound.recv().await {
+                    // nothing is sent after the close frame
+                    let close = msg.is_close();
+                    if sink.send(msg).is_err() || close {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Attach a user-defined session payload to this connection,
+    /// replacing any previously stored payload. The payload is
+    /// dropped together with the connection context.
+    pub fn set_session<T>(&self, session : T)
+    where
+        T : Any + Send + Sync
+    {
+        *self.session.lock().unwrap() = Some(Arc::new(session));
+    }
+
+    /// Obtain the session payload if one is present and is of type `T`.
+    pub fn session<T>(&self) -> Option<Arc<T>>
+    where
+        T : Any + Send + Sync
+    {
+        let session = self.session.lock().unwrap().clone()?;
+        session.downcast::<T>().ok()
+    }
+
+    /// Remove the session payload from this connection.
+    pub fn clear_session(&self) {
+        self.session.lock().unwrap().take();
+    }
+
+    /// Post a raw notification to the peer.
+    pub fn notify_with_buffer(&self, op : u32, data : &[u8]) -> Result<()> {
+        let msg = notification_to_vec(op, data).map_err(|_| Error::BorshSerialize)?;
+        self.post(msg)
+    }
+
+    /// Close the connection, sending `reason` to the peer. Frames
+    /// posted before the close frame are delivered ahead of it.
+    pub fn close(&self, reason : &str) -> Result<()> {
+        self.close_with(CloseCode::Normal, reason)
+    }
+
+    pub(crate) fn close_with(&self, code : CloseCode, reason : &str) -> Result<()> {
+        let frame = CloseFrame {
+            code,
+            reason : reason.to_string().into(),
+        };
+        self.closed.store(true, Ordering::SeqCst);
+        self.post_message(tungstenite::Message::Close(Some(frame)))
+    }
+
+    /// Whether `close()` has been called; frames received from the
+    /// peer afterwards are not processed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Post a serialized frame to the peer.
+    pub(crate) fn post(&self, frame : Vec<u8>) -> Result<()> {
+        self.post_message(frame.into())
+    }
+
+    /// Queue a message for the peer. Every frame sent on the connection,
+    /// including responses, passes through this queue, so frames reach
+    /// the peer in the order they were posted.
+    pub(crate) fn post_message(&self, msg : tungstenite::Message) -> Result<()> {
+        self.sink.send(msg).map_err(|e| Error::SinkSend(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Serialize `msg` and post it to the peer as a notification for `op`.
+    pub fn notify<Op, Msg>(&self, op : Op, msg : &Msg) -> Result<()>
+    where
+        Op : Into<u32>,
+        Msg : BorshSerialize,
+    {
+        let data = msg.try_to_vec().map_err(|_| Error::BorshSerialize)?;
+        self.notify_with_buffer(op.into(), &data)
+    }
+}
@@ -6,4 +6,16 @@ pub enum Error {
     #[error("WebSocket error: {0}")]
     WebSocketError(#[from] workflow_websocket::server::Error),
 
+    /// Unable to post a message to the connection sink
+    #[error("Sink error: {0}")]
+    SinkSend(String),
+
+    /// Unable to serialize borsh data
+    #[error("RPC: borsh serialization error")]
+    BorshSerialize,
+
+    /// No active connection with the given id
+    #[error("Connection {0} not found")]
+    ConnectionNotFound(u64),
+
 }
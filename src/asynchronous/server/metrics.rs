@@ -0,0 +1,126 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use ahash::AHashMap;
+use crate::asynchronous::histogram::Histogram;
+use crate::asynchronous::result::RpcResult;
+use crate::asynchronous::error::RpcResponseError;
+
+/// Counters collected for a single op.
+#[derive(Debug, Clone, Default)]
+pub struct RpcOpMetrics {
+    pub requests : u64,
+    pub successes : u64,
+    /// Error counts keyed by [`RpcResponseError::kind()`](crate::asynchronous::error::RpcResponseError::kind)
+    pub errors : AHashMap<&'static str, u64>,
+    pub in_flight : u64,
+    /// Requests aborted by a client cancellation or a disconnect
+    pub cancelled : u64,
+    pub bytes_received : u64,
+    pub bytes_sent : u64,
+    pub latency : Histogram,
+}
+
+/// Per-op request metrics recorded by the server for binary requests.
+#[derive(Default)]
+pub struct RpcMetrics {
+    ops : Mutex<AHashMap<u32, RpcOpMetrics>>,
+}
+
+impl RpcMetrics {
+    pub(crate) fn begin(&self, op : u32, bytes_received : usize) {
+        let mut ops = self.ops.lock().unwrap();
+        let metrics = ops.entry(op).or_default();
+        metrics.requests += 1;
+        metrics.in_flight += 1;
+        metrics.bytes_received += bytes_received as u64;
+    }
+
+    pub(crate) fn received(&self, op : u32, bytes_received : usize) {
+        self.ops.lock().unwrap().entry(op).or_default().bytes_received += bytes_received as u64;
+    }
+
+    pub(crate) fn end(&self, op : u32, elapsed : Duration, result : &RpcResult) {
+        match result {
+            Ok(data) => self.end_with(op, elapsed, data.len(), None),
+            Err(err) => self.end_with(op, elapsed, 0, Some(err)),
+        }
+    }
+
+    pub(crate) fn end_with(&self, op : u32, elapsed : Duration, bytes_sent : usize, error : Option<&RpcResponseError>) {
+        let mut ops = self.ops.lock().unwrap();
+        let metrics = ops.entry(op).or_default();
+        metrics.in_flight = metrics.in_flight.saturating_sub(1);
+        metrics.latency.record(elapsed);
+        metrics.bytes_sent += bytes_sent as u64;
+        match error {
+            None => {
+                metrics.successes += 1;
+            },
+            Some(err) => {
+                *metrics.errors.entry(err.kind()).or_default() += 1;
+            }
+        }
+    }
+
+    pub(crate) fn cancel(&self, op : u32) {
+        let mut ops = self.ops.lock().unwrap();
+        let metrics = ops.entry(op).or_default();
+        metrics.in_flight = metrics.in_flight.saturating_sub(1);
+        metrics.cancelled += 1;
+    }
+
+    /// Copy of the metrics collected so far, keyed by op.
+    pub fn snapshot(&self) -> AHashMap<u32, RpcOpMetrics> {
+        self.ops.lock().unwrap().clone()
+    }
+
+    pub fn reset(&self) {
+        self.ops.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_counted_per_op() {
+        let metrics = RpcMetrics::default();
+        metrics.begin(1, 10);
+        metrics.begin(1, 5);
+        metrics.begin(2, 1);
+        metrics.end(1, Duration::from_millis(2), &Ok(vec![0; 3]));
+        metrics.cancel(2);
+
+        let snapshot = metrics.snapshot();
+        let op = &snapshot[&1];
+        assert_eq!((op.requests, op.successes, op.in_flight, op.cancelled), (2, 1, 1, 0));
+        assert_eq!((op.bytes_received, op.bytes_sent), (15, 3));
+        assert_eq!(op.latency.mean(), Some(Duration::from_millis(2)));
+        let op = &snapshot[&2];
+        assert_eq!((op.requests, op.successes, op.in_flight, op.cancelled), (1, 0, 0, 1));
+
+        metrics.reset();
+        assert!(metrics.snapshot().is_empty());
+    }
+
+    #[test]
+    fn errors_are_grouped_by_kind() {
+        let metrics = RpcMetrics::default();
+        for result in [
+            Err(RpcResponseError::NotFound),
+            Err(RpcResponseError::Text("first".to_string())),
+            Err(RpcResponseError::Text("second".to_string())),
+        ] {
+            metrics.begin(1, 0);
+            metrics.end(1, Duration::ZERO, &result);
+        }
+
+        let op = &metrics.snapshot()[&1];
+        assert_eq!(op.successes, 0);
+        assert_eq!(op.in_flight, 0);
+        assert_eq!(op.errors.len(), 2);
+        assert_eq!(op.errors["NotFound"], 1);
+        assert_eq!(op.errors["Text"], 2);
+    }
+}
@@ -0,0 +1,390 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use async_trait::async_trait;
+use workflow_websocket::server::Result as WebSocketResult;
+use crate::asynchronous::error::RpcResponseError;
+use crate::asynchronous::result::RpcResult;
+use super::context::RpcContext;
+use super::server::{RpcHandler, RpcStream};
+use super::upload::RpcUpload;
+
+/// Remainder of the handler chain supplied to an [`RpcMiddleware`].
+pub struct Next<Ops>
+where
+    Ops : Send + Sync + 'static
+{
+    handler : Arc<dyn RpcHandler<Ops>>,
+}
+
+impl<Ops> Next<Ops>
+where
+    Ops : Send + Sync + 'static
+{
+    /// Pass the request to the next layer or to the wrapped handler.
+    pub async fn run(self, ctx : Arc<RpcContext>, op : Ops, data : &[u8]) -> RpcResult {
+        self.handler.handle_request(ctx, op, data).await
+    }
+
+    /// Pass the stream request to the next layer or to the wrapped handler.
+    pub async fn run_stream(self, ctx : Arc<RpcContext>, op : Ops, data : &[u8]) -> Result<RpcStream, RpcResponseError> {
+        self.handler.handle_stream_request(ctx, op, data).await
+    }
+
+    /// Pass the subscription to the next layer or to the wrapped handler.
+    pub async fn run_subscribe(self, ctx : Arc<RpcContext>, topic : &str) -> Result<(), RpcResponseError> {
+        self.handler.subscribe(ctx, topic).await
+    }
+
+    /// Pass the upload to the next layer or to the wrapped handler.
+    pub async fn run_upload(self, ctx : Arc<RpcContext>, op : Ops, data : &[u8], upload : RpcUpload) -> RpcResult {
+        self.handler.handle_upload(ctx, op, data, upload).await
+    }
+}
+
+/// Interceptor wrapping an [`RpcHandler`]. A middleware can inspect
+/// the connection context, op and payload, short-circuit by returning
+/// a result without calling [`Next::run`], or inspect the result
+/// produced by the rest of the chain.
+///
+/// Streams, subscriptions and uploads pass through their own hooks,
+/// which hand the request to the rest of the chain unless implemented.
+/// A middleware enforcing access rules must implement all of them.
+#[async_trait]
+pub trait RpcMiddleware<Ops> : Send + Sync + 'static
+where
+    Ops : Send + Sync + 'static
+{
+    async fn handle(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8], next : Next<Ops>) -> RpcResult;
+
+    async fn handle_stream(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8], next : Next<Ops>) -> Result<RpcStream, RpcResponseError> {
+        next.run_stream(ctx, op, data).await
+    }
+
+    async fn subscribe(self : Arc<Self>, ctx : Arc<RpcContext>, topic : &str, next : Next<Ops>) -> Result<(), RpcResponseError> {
+        next.run_subscribe(ctx, topic).await
+    }
+
+    async fn handle_upload(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8], upload : RpcUpload, next : Next<Ops>) -> RpcResult {
+        next.run_upload(ctx, op, data, upload).await
+    }
+}
+
+struct Layer<Ops>
+where
+    Ops : Send + Sync + 'static
+{
+    middleware : Arc<dyn RpcMiddleware<Ops>>,
+    inner : Arc<dyn RpcHandler<Ops>>,
+}
+
+#[async_trait]
+impl<Ops> RpcHandler<Ops> for Layer<Ops>
+where
+    Ops : Send + Sync + 'static
+{
+    async fn connect(self : Arc<Self>, ctx : Arc<RpcContext>) -> WebSocketResult<()> {
+        self.inner.clone().connect(ctx).await
+    }
+
+    async fn handshake(self : Arc<Self>, ctx : Arc<RpcContext>) -> Result<(), RpcResponseError> {
+        self.inner.clone().handshake(ctx).await
+    }
+
+    async fn disconnect(self : Arc<Self>, ctx : Arc<RpcContext>) {
+        self.inner.clone().disconnect(ctx).await
+    }
+
+    async fn handle_request(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8]) -> RpcResult {
+        let next = Next { handler : self.inner.clone() };
+        self.middleware.clone().handle(ctx, op, data, next).await
+    }
+
+    async fn handle_stream_request(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8]) -> Result<RpcStream, RpcResponseError> {
+        let next = Next { handler : self.inner.clone() };
+        self.middleware.clone().handle_stream(ctx, op, data, next).await
+    }
+
+    async fn subscribe(self : Arc<Self>, ctx : Arc<RpcContext>, topic : &str) -> Result<(), RpcResponseError> {
+        let next = Next { handler : self.inner.clone() };
+        self.middleware.clone().subscribe(ctx, topic, next).await
+    }
+
+    async fn handle_upload(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8], upload : RpcUpload) -> RpcResult {
+        let next = Next { handler : self.inner.clone() };
+        self.middleware.clone().handle_upload(ctx, op, data, upload, next).await
+    }
+}
+
+/// Composes middleware around an [`RpcHandler`]. The last layer
+/// added is the outermost one and sees each request first.
+///
+/// ```ignore
+/// let handler = RpcStack::new(handler)
+///     .layer(Arc::new(PayloadLimit::new(64 * 1024)))
+///     .layer(Arc::new(RequireIdentity::new()))
+///     .build();
+/// let server = RpcServer::new(handler);
+/// ```
+pub struct RpcStack<Ops>
+where
+    Ops : Send + Sync + 'static
+{
+    handler : Arc<dyn RpcHandler<Ops>>,
+}
+
+impl<Ops> RpcStack<Ops>
+where
+    Ops : Send + Sync + 'static
+{
+    pub fn new(handler : Arc<dyn RpcHandler<Ops>>) -> Self {
+        RpcStack { handler }
+    }
+
+    pub fn layer(self, middleware : Arc<dyn RpcMiddleware<Ops>>) -> Self {
+        RpcStack {
+            handler : Arc::new(Layer { middleware, inner : self.handler }),
+        }
+    }
+
+    pub fn build(self) -> Arc<dyn RpcHandler<Ops>> {
+        self.handler
+    }
+}
+
+/// Rejects requests whose payload exceeds `max_size` bytes. Uploads
+/// are also rejected once the uploaded content exceeds `max_size`.
+pub struct PayloadLimit {
+    max_size : usize,
+}
+
+impl PayloadLimit {
+    pub fn new(max_size : usize) -> Self {
+        PayloadLimit { max_size }
+    }
+}
+
+#[async_trait]
+impl<Ops> RpcMiddleware<Ops> for PayloadLimit
+where
+    Ops : Send + Sync + 'static
+{
+    async fn handle(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8], next : Next<Ops>) -> RpcResult {
+        if data.len() > self.max_size {
+            return Err(RpcResponseError::PayloadTooLarge);
+        }
+        next.run(ctx, op, data).await
+    }
+
+    async fn handle_stream(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8], next : Next<Ops>) -> Result<RpcStream, RpcResponseError> {
+        if data.len() > self.max_size {
+            return Err(RpcResponseError::PayloadTooLarge);
+        }
+        next.run_stream(ctx, op, data).await
+    }
+
+    async fn handle_upload(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8], mut upload : RpcUpload, next : Next<Ops>) -> RpcResult {
+        if data.len() > self.max_size {
+            return Err(RpcResponseError::PayloadTooLarge);
+        }
+        let exceeded = upload.limit(self.max_size);
+        let result = next.run_upload(ctx, op, data, upload).await;
+        if exceeded.load(Ordering::SeqCst) {
+            return Err(RpcResponseError::PayloadTooLarge);
+        }
+        result
+    }
+}
+
+/// Rejects requests from connections without an identity
+/// established by the handshake or, if a role is specified,
+/// whose identity does not carry that role.
+pub struct RequireIdentity {
+    role : Option<String>,
+}
+
+impl RequireIdentity {
+    pub fn new() -> Self {
+        RequireIdentity { role : None }
+    }
+
+    pub fn with_role(role : &str) -> Self {
+        RequireIdentity { role : Some(role.to_string()) }
+    }
+}
+
+impl RequireIdentity {
+    fn authorize(&self, ctx : &RpcContext) -> Result<(), RpcResponseError> {
+        let authorized = match (ctx.identity(), &self.role) {
+            (Some(identity), Some(role)) => identity.has_role(role),
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+
+        if !authorized {
+            return Err(RpcResponseError::Forbidden);
+        }
+        Ok(())
+    }
+}
+
+impl Default for RequireIdentity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// every hook is implemented, so that no kind of request bypasses the check
+#[async_trait]
+impl<Ops> RpcMiddleware<Ops> for RequireIdentity
+where
+    Ops : Send + Sync + 'static
+{
+    async fn handle(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8], next : Next<Ops>) -> RpcResult {
+        self.authorize(&ctx)?;
+        next.run(ctx, op, data).await
+    }
+
+    async fn handle_stream(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8], next : Next<Ops>) -> Result<RpcStream, RpcResponseError> {
+        self.authorize(&ctx)?;
+        next.run_stream(ctx, op, data).await
+    }
+
+    async fn subscribe(self : Arc<Self>, ctx : Arc<RpcContext>, topic : &str, next : Next<Ops>) -> Result<(), RpcResponseError> {
+        self.authorize(&ctx)?;
+        next.run_subscribe(ctx, topic).await
+    }
+
+    async fn handle_upload(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8], upload : RpcUpload, next : Next<Ops>) -> RpcResult {
+        self.authorize(&ctx)?;
+        next.run_upload(ctx, op, data, upload).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use crate::asynchronous::auth::RpcIdentity;
+
+    struct Echo;
+
+    #[async_trait]
+    impl RpcHandler<u32> for Echo {
+        async fn handle_request(self : Arc<Self>, _ctx : Arc<RpcContext>, _op : u32, data : &[u8]) -> RpcResult {
+            Ok(data.to_vec())
+        }
+
+        async fn subscribe(self : Arc<Self>, _ctx : Arc<RpcContext>, _topic : &str) -> Result<(), RpcResponseError> {
+            Ok(())
+        }
+
+        async fn handle_upload(self : Arc<Self>, _ctx : Arc<RpcContext>, _op : u32, _data : &[u8], mut upload : RpcUpload) -> RpcResult {
+            let mut data = Vec::new();
+            while let Some(chunk) = upload.recv().await {
+                data.extend(chunk);
+            }
+            Ok(data)
+        }
+    }
+
+    /// Records the order in which layers see a request; rejects
+    /// requests for `op` 0 without calling the rest of the chain.
+    struct Trace {
+        name : &'static str,
+        log : Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl RpcMiddleware<u32> for Trace {
+        async fn handle(self : Arc<Self>, ctx : Arc<RpcContext>, op : u32, data : &[u8], next : Next<u32>) -> RpcResult {
+            self.log.lock().unwrap().push(self.name);
+            if op == 0 {
+                return Err(RpcResponseError::Forbidden);
+            }
+            next.run(ctx, op, data).await
+        }
+    }
+
+    fn ctx() -> Arc<RpcContext> {
+        Arc::new(RpcContext::new("127.0.0.1:1".parse().unwrap(), 1))
+    }
+
+    fn traced(log : &Arc<Mutex<Vec<&'static str>>>) -> Arc<dyn RpcHandler<u32>> {
+        RpcStack::new(Arc::new(Echo))
+            .layer(Arc::new(Trace { name : "inner", log : log.clone() }))
+            .layer(Arc::new(Trace { name : "outer", log : log.clone() }))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn the_last_layer_sees_requests_first() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let resp = traced(&log).handle_request(ctx(), 1, &[1, 2]).await.unwrap();
+        assert_eq!(resp, vec![1, 2]);
+        assert_eq!(*log.lock().unwrap(), vec!["outer", "inner"]);
+    }
+
+    #[tokio::test]
+    async fn a_layer_can_short_circuit_the_chain() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let err = traced(&log).handle_request(ctx(), 0, &[]).await.unwrap_err();
+        assert_eq!(err.kind(), "Forbidden");
+        assert_eq!(*log.lock().unwrap(), vec!["outer"]);
+    }
+
+    #[tokio::test]
+    async fn unimplemented_hooks_pass_requests_through() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        traced(&log).subscribe(ctx(), "topic").await.unwrap();
+        assert!(log.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn payload_limit_rejects_large_requests() {
+        let handler = RpcStack::new(Arc::new(Echo)).layer(Arc::new(PayloadLimit::new(2))).build();
+        assert_eq!(handler.clone().handle_request(ctx(), 1, &[1, 2]).await.unwrap(), vec![1, 2]);
+        let err = handler.clone().handle_request(ctx(), 1, &[1, 2, 3]).await.unwrap_err();
+        assert_eq!(err.kind(), "PayloadTooLarge");
+        let err = handler.handle_stream_request(ctx(), 1, &[1, 2, 3]).await.err().unwrap();
+        assert_eq!(err.kind(), "PayloadTooLarge");
+    }
+
+    #[tokio::test]
+    async fn payload_limit_ends_large_uploads() {
+        let handler = RpcStack::new(Arc::new(Echo)).layer(Arc::new(PayloadLimit::new(2))).build();
+        let ctx = ctx();
+        let (sender, receiver) = tokio::sync::mpsc::channel(4);
+        sender.send(vec![1, 2]).await.unwrap();
+        sender.send(vec![3]).await.unwrap();
+        drop(sender);
+
+        let upload = RpcUpload::new(1, receiver, ctx.clone(), 4);
+        let err = handler.handle_upload(ctx, 1, &[], upload).await.unwrap_err();
+        assert_eq!(err.kind(), "PayloadTooLarge");
+    }
+
+    #[tokio::test]
+    async fn require_identity_checks_every_kind_of_request() {
+        let handler = RpcStack::new(Arc::new(Echo)).layer(Arc::new(RequireIdentity::with_role("admin"))).build();
+        let ctx = ctx();
+
+        let err = handler.clone().handle_request(ctx.clone(), 1, &[]).await.unwrap_err();
+        assert_eq!(err.kind(), "Forbidden");
+        let err = handler.clone().handle_stream_request(ctx.clone(), 1, &[]).await.err().unwrap();
+        assert_eq!(err.kind(), "Forbidden");
+        let err = handler.clone().subscribe(ctx.clone(), "topic").await.unwrap_err();
+        assert_eq!(err.kind(), "Forbidden");
+        let (_sender, receiver) = tokio::sync::mpsc::channel(1);
+        let upload = RpcUpload::new(1, receiver, ctx.clone(), 1);
+        let err = handler.clone().handle_upload(ctx.clone(), 1, &[], upload).await.unwrap_err();
+        assert_eq!(err.kind(), "Forbidden");
+
+        ctx.set_identity(RpcIdentity::new("user", &["user"]));
+        let err = handler.clone().handle_request(ctx.clone(), 1, &[]).await.unwrap_err();
+        assert_eq!(err.kind(), "Forbidden");
+
+        ctx.set_identity(RpcIdentity::new("admin", &["admin"]));
+        assert_eq!(handler.clone().handle_request(ctx.clone(), 1, &[1]).await.unwrap(), vec![1]);
+        handler.subscribe(ctx, "topic").await.unwrap();
+    }
+}
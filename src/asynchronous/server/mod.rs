@@ -1,15 +1,44 @@
 pub use super::error::*;
 pub use super::message::*;
 pub use super::ops::*;
+pub use super::jsonrpc::*;
+pub use super::stream::*;
+pub use super::pubsub::*;
 
 mod server;
 pub use self::server::*;
 
+mod context;
+pub use self::context::*;
+
+mod router;
+pub use self::router::*;
+
+mod middleware;
+pub use self::middleware::*;
+
+mod upload;
+pub use self::upload::*;
+
+mod connections;
+pub use self::connections::*;
+
+mod pubsub;
+pub use self::pubsub::*;
+
+mod metrics;
+pub use self::metrics::*;
+pub use crate::asynchronous::histogram::*;
+
+mod auth;
+pub use self::auth::*;
+pub use crate::asynchronous::auth::RpcIdentity;
+
 // mod with_borsh;
 // pub use self::with_borsh::*;
 
-// mod with_serde;
-// pub use self::with_serde::*;
+mod with_serde;
+pub use self::with_serde::*;
 
 pub mod error;
 // pub use self::error::*;
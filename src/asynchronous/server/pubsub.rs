@@ -0,0 +1,85 @@
+use std::sync::{Arc, Mutex};
+use ahash::AHashMap;
+use borsh::BorshSerialize;
+use workflow_log::log_trace;
+use crate::asynchronous::message::*;
+use crate::asynchronous::pubsub::Publication;
+use super::context::RpcContext;
+use super::error::Error;
+use super::result::Result;
+
+/// Subscriber sets per topic. Connections are removed from
+/// all topics when they disconnect.
+#[derive(Default)]
+pub struct RpcTopics {
+    topics : Mutex<AHashMap<String, AHashMap<u64, Arc<RpcContext>>>>,
+}
+
+impl RpcTopics {
+    pub(crate) fn subscribe(&self, topic : &str, ctx : &Arc<RpcContext>) {
+        ctx.add_topic(topic);
+        self.topics.lock().unwrap()
+            .entry(topic.to_string())
+            .or_default()
+            .insert(ctx.id, ctx.clone());
+    }
+
+    pub(crate) fn unsubscribe(&self, topic : &str, ctx : &RpcContext) {
+        ctx.remove_topic(topic);
+        let mut topics = self.topics.lock().unwrap();
+        if let Some(subscribers) = topics.get_mut(topic) {
+            subscribers.remove(&ctx.id);
+            if subscribers.is_empty() {
+                topics.remove(topic);
+            }
+        }
+    }
+
+    pub(crate) fn remove_connection(&self, ctx : &RpcContext) {
+        for topic in ctx.take_topics() {
+            self.unsubscribe(&topic, ctx);
+        }
+    }
+
+    /// Post raw data to every subscriber of `topic`. The frame is
+    /// serialized once and shared by all subscribers. Returns the
+    /// number of subscribers the data was posted to.
+    pub fn publish_with_buffer(&self, topic : &str, data : &[u8]) -> Result<usize> {
+        let subscribers : Vec<Arc<RpcContext>> = match self.topics.lock().unwrap().get(topic) {
+            Some(subscribers) => subscribers.values().cloned().collect(),
+            None => { return Ok(0); }
+        };
+
+        let publication = Publication { topic : topic.to_string(), data : data.to_vec() };
+        let payload = publication.try_to_vec().map_err(|_| Error::BorshSerialize)?;
+        let frame = RespMessage::new(0, RespStatus::Publish as u32, &payload).try_to_vec().map_err(|_| Error::BorshSerialize)?;
+
+        let mut delivered = 0;
+        for ctx in subscribers {
+            match ctx.post(frame.clone()) {
+                Ok(_) => { delivered += 1; },
+                Err(err) => { log_trace!("RPC unable to publish to {}: {}", ctx.peer, err); }
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Serialize `msg` and publish it to every subscriber of `topic`.
+    pub fn publish<Msg>(&self, topic : &str, msg : &Msg) -> Result<usize>
+    where
+        Msg : BorshSerialize
+    {
+        let data = msg.try_to_vec().map_err(|_| Error::BorshSerialize)?;
+        self.publish_with_buffer(topic, &data)
+    }
+
+    /// Number of connections subscribed to `topic`.
+    pub fn subscribers(&self, topic : &str) -> usize {
+        self.topics.lock().unwrap().get(topic).map(|subscribers| subscribers.len()).unwrap_or(0)
+    }
+
+    /// Topics with at least one subscriber.
+    pub fn topics(&self) -> Vec<String> {
+        self.topics.lock().unwrap().keys().cloned().collect()
+    }
+}
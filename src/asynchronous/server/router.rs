@@ -0,0 +1,268 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use ahash::AHashMap;
+use async_trait::async_trait;
+use borsh::{BorshSerialize,BorshDeserialize};
+use futures::future::BoxFuture;
+use futures::stream::{Stream, StreamExt};
+use crate::asynchronous::error::RpcResponseError;
+use crate::asynchronous::result::RpcResult;
+use super::context::RpcContext;
+use super::server::{RpcHandler, RpcStream};
+use super::upload::RpcUpload;
+
+pub type RpcMethodFn = Arc<Box<(dyn Fn(Arc<RpcContext>, &[u8]) -> BoxFuture<'static, RpcResult> + Sync + Send)>>;
+pub type RpcUploadFn = Arc<Box<(dyn Fn(Arc<RpcContext>, &[u8], RpcUpload) -> BoxFuture<'static, RpcResult> + Sync + Send)>>;
+pub type RpcStreamFn = Arc<Box<(dyn Fn(Arc<RpcContext>, &[u8]) -> BoxFuture<'static, Result<RpcStream, RpcResponseError>> + Sync + Send)>>;
+
+/// [`RpcHandler`] that dispatches each op to an async function
+/// registered with [`RpcRouter::method`]. The router decodes the
+/// request, encodes the response and maps failures to
+/// [`RpcResponseError`]. Requests for unregistered ops produce
+/// an unknown op response.
+pub struct RpcRouter<Ops>
+where
+    Ops : Into<u32> + Send + Sync + 'static
+{
+    methods : AHashMap<u32, RpcMethodFn>,
+    streams : AHashMap<u32, RpcStreamFn>,
+    uploads : AHashMap<u32, RpcUploadFn>,
+    _ops_ : PhantomData<Ops>,
+}
+
+impl<Ops> RpcRouter<Ops>
+where
+    Ops : Into<u32> + Send + Sync + 'static
+{
+    pub fn new() -> Self {
+        RpcRouter {
+            methods : AHashMap::new(),
+            streams : AHashMap::new(),
+            uploads : AHashMap::new(),
+            _ops_ : PhantomData,
+        }
+    }
+
+    /// Register a method receiving raw request data for `op`.
+    pub fn method_with_buffer(mut self, op : Ops, method : RpcMethodFn) -> Self {
+        self.methods.insert(op.into(), method);
+        self
+    }
+
+    /// Register a typed async method for `op`, replacing any
+    /// method previously registered for the same op.
+    pub fn method<Req, Resp, F, Fut>(self, op : Ops, method : F) -> Self
+    where
+        Req : BorshDeserialize + Send + 'static,
+        Resp : BorshSerialize + Send + 'static,
+        F : Fn(Arc<RpcContext>, Req) -> Fut + Send + Sync + 'static,
+        Fut : Future<Output = Result<Resp, RpcResponseError>> + Send + 'static,
+    {
+        let method = Arc::new(method);
+        self.method_with_buffer(op, Arc::new(Box::new(move |ctx : Arc<RpcContext>, data : &[u8]| -> BoxFuture<'static, RpcResult> {
+            let req = Req::try_from_slice(data);
+            let method = method.clone();
+            Box::pin(async move {
+                let req = req.map_err(|_| RpcResponseError::ReqDeserialize)?;
+                let resp = method(ctx, req).await?;
+                resp.try_to_vec().map_err(|_| RpcResponseError::RespSerialize)
+            })
+        })))
+    }
+
+    /// Register a streaming method receiving raw request data for `op`.
+    pub fn stream_with_buffer(mut self, op : Ops, method : RpcStreamFn) -> Self {
+        self.streams.insert(op.into(), method);
+        self
+    }
+
+    /// Register a typed async method producing a stream of
+    /// responses for `op`, replacing any streaming method
+    /// previously registered for the same op.
+    pub fn stream<Req, Resp, F, Fut, S>(self, op : Ops, method : F) -> Self
+    where
+        Req : BorshDeserialize + Send + 'static,
+        Resp : BorshSerialize + Send + 'static,
+        F : Fn(Arc<RpcContext>, Req) -> Fut + Send + Sync + 'static,
+        Fut : Future<Output = Result<S, RpcResponseError>> + Send + 'static,
+        S : Stream<Item = Result<Resp, RpcResponseError>> + Send + 'static,
+    {
+        let method = Arc::new(method);
+        self.stream_with_buffer(op, Arc::new(Box::new(move |ctx : Arc<RpcContext>, data : &[u8]| -> BoxFuture<'static, Result<RpcStream, RpcResponseError>> {
+            let req = Req::try_from_slice(data);
+            let method = method.clone();
+            Box::pin(async move {
+                let req = req.map_err(|_| RpcResponseError::ReqDeserialize)?;
+                let stream = method(ctx, req).await?;
+                Ok(stream.map(|item| {
+                    item.and_then(|resp| resp.try_to_vec().map_err(|_| RpcResponseError::RespSerialize))
+                }).boxed())
+            })
+        })))
+    }
+
+    /// Register an upload method receiving raw request data for `op`.
+    pub fn upload_with_buffer(mut self, op : Ops, method : RpcUploadFn) -> Self {
+        self.uploads.insert(op.into(), method);
+        self
+    }
+
+    /// Register a typed async method for `op` receiving a client
+    /// upload, replacing any upload method previously registered
+    /// for the same op.
+    pub fn upload<Req, Resp, F, Fut>(self, op : Ops, method : F) -> Self
+    where
+        Req : BorshDeserialize + Send + 'static,
+        Resp : BorshSerialize + Send + 'static,
+        F : Fn(Arc<RpcContext>, Req, RpcUpload) -> Fut + Send + Sync + 'static,
+        Fut : Future<Output = Result<Resp, RpcResponseError>> + Send + 'static,
+    {
+        let method = Arc::new(method);
+        self.upload_with_buffer(op, Arc::new(Box::new(move |ctx : Arc<RpcContext>, data : &[u8], upload : RpcUpload| -> BoxFuture<'static, RpcResult> {
+            let req = Req::try_from_slice(data);
+            let method = method.clone();
+            Box::pin(async move {
+                let req = req.map_err(|_| RpcResponseError::ReqDeserialize)?;
+                let resp = method(ctx, req, upload).await?;
+                resp.try_to_vec().map_err(|_| RpcResponseError::RespSerialize)
+            })
+        })))
+    }
+
+    pub fn contains(&self, op : Ops) -> bool {
+        let op = op.into();
+        self.methods.contains_key(&op) || self.streams.contains_key(&op) || self.uploads.contains_key(&op)
+    }
+}
+
+impl<Ops> Default for RpcRouter<Ops>
+where
+    Ops : Into<u32> + Send + Sync + 'static
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl<Ops> RpcHandler<Ops> for RpcRouter<Ops>
+where
+    Ops : Into<u32> + Send + Sync + 'static
+{
+    async fn handle_request(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8]) -> RpcResult {
+        let method = self.methods.get(&op.into()).cloned();
+        match method {
+            Some(method) => method(ctx, data).await,
+            None => Err(RpcResponseError::NotFound),
+        }
+    }
+
+    async fn handle_stream_request(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8]) -> Result<RpcStream, RpcResponseError> {
+        let method = self.streams.get(&op.into()).cloned();
+        match method {
+            Some(method) => method(ctx, data).await,
+            None => Err(RpcResponseError::NotFound),
+        }
+    }
+
+    async fn handle_upload(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8], upload : RpcUpload) -> RpcResult {
+        let method = self.uploads.get(&op.into()).cloned();
+        match method {
+            Some(method) => method(ctx, data, upload).await,
+            None => Err(RpcResponseError::NotFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum TestOps {
+        Add = 0,
+        Count = 1,
+        Sum = 2,
+        Missing = 3,
+    }
+
+    impl From<TestOps> for u32 {
+        fn from(op : TestOps) -> u32 {
+            op as u32
+        }
+    }
+
+    fn router() -> Arc<RpcRouter<TestOps>> {
+        Arc::new(RpcRouter::new()
+            .method(TestOps::Add, |_ctx, (a, b) : (u32, u32)| async move {
+                Ok(a + b)
+            })
+            .stream(TestOps::Count, |_ctx, count : u32| async move {
+                Ok(futures::stream::iter((0..count).map(Ok)))
+            })
+            .upload(TestOps::Sum, |_ctx, base : u32, mut upload : RpcUpload| async move {
+                let mut sum = base;
+                while let Some(chunk) = upload.recv().await {
+                    sum += chunk.iter().map(|byte| *byte as u32).sum::<u32>();
+                }
+                Ok(sum)
+            }))
+    }
+
+    fn ctx() -> Arc<RpcContext> {
+        Arc::new(RpcContext::new("127.0.0.1:1".parse().unwrap(), 1))
+    }
+
+    #[tokio::test]
+    async fn methods_are_dispatched_by_op() {
+        let router = router();
+        let req = (2u32, 3u32).try_to_vec().unwrap();
+        let resp = router.handle_request(ctx(), TestOps::Add, &req).await.unwrap();
+        assert_eq!(u32::try_from_slice(&resp).unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn undecodable_requests_are_rejected() {
+        let router = router();
+        let err = router.handle_request(ctx(), TestOps::Add, &[1]).await.unwrap_err();
+        assert_eq!(err.kind(), "ReqDeserialize");
+    }
+
+    #[tokio::test]
+    async fn streams_are_dispatched_by_op() {
+        let router = router();
+        let stream = router.handle_stream_request(ctx(), TestOps::Count, &3u32.try_to_vec().unwrap()).await.unwrap();
+        let items : Vec<u32> = stream.map(|item| u32::try_from_slice(&item.unwrap()).unwrap()).collect().await;
+        assert_eq!(items, vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn uploads_are_dispatched_by_op() {
+        let router = router();
+        let ctx = ctx();
+        let (sender, receiver) = tokio::sync::mpsc::channel(4);
+        sender.send(vec![1, 2]).await.unwrap();
+        sender.send(vec![3]).await.unwrap();
+        drop(sender);
+
+        let upload = RpcUpload::new(1, receiver, ctx.clone(), 4);
+        let resp = router.handle_upload(ctx, TestOps::Sum, &10u32.try_to_vec().unwrap(), upload).await.unwrap();
+        assert_eq!(u32::try_from_slice(&resp).unwrap(), 16);
+    }
+
+    #[tokio::test]
+    async fn unregistered_ops_are_not_found() {
+        let router = router();
+        assert!(router.contains(TestOps::Add));
+        assert!(!router.contains(TestOps::Missing));
+
+        let err = router.clone().handle_request(ctx(), TestOps::Missing, &[]).await.unwrap_err();
+        assert_eq!(err.kind(), "NotFound");
+        // ops are registered for one kind of request only
+        let err = router.clone().handle_request(ctx(), TestOps::Count, &[]).await.unwrap_err();
+        assert_eq!(err.kind(), "NotFound");
+        let err = router.handle_stream_request(ctx(), TestOps::Add, &[]).await.err().unwrap();
+        assert_eq!(err.kind(), "NotFound");
+    }
+}
@@ -1,16 +1,32 @@
+use std::mem::size_of;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 use async_trait::async_trait;
+use futures::future::{AbortHandle, AbortRegistration, Abortable};
+use futures::stream::{BoxStream, StreamExt};
 use workflow_websocket::server::WebSocketHandler;
 use crate::asynchronous::message::*;
 use crate::asynchronous::error::RpcResponseError;
 use tokio::sync::mpsc::*;
+use tokio::sync::Semaphore;
 use workflow_log::*;
 use workflow_websocket::server::{
     WebSocketServer, Result as WebSocketResult
 };
 use tungstenite::Message;
-use borsh::BorshSerialize;
+use tungstenite::protocol::frame::coding::CloseCode;
+use borsh::{BorshSerialize,BorshDeserialize};
+use ahash::AHashMap;
+use crate::asynchronous::auth::*;
+use crate::asynchronous::jsonrpc::*;
+use super::context::{RpcContext, Turn};
+use super::auth::*;
+use super::with_serde::*;
+use super::metrics::*;
+use super::upload::RpcUpload;
+use super::pubsub::RpcTopics;
+use super::connections::RpcConnections;
 
 
 pub fn result<Resp>(resp:Resp) -> Result<Option<Vec<u8>>,RpcResponseError>
@@ -19,17 +35,155 @@ where Resp : BorshSerialize {
     Ok(Some(data))
 }
 
-pub struct RpcContext {
-    pub peer : SocketAddr,
-}
-
+/// Sequence of serialized items produced by a streaming op.
+/// An error item terminates the stream.
+pub type RpcStream = BoxStream<'static, Result<Vec<u8>, RpcResponseError>>;
 
 #[async_trait]
 pub trait RpcHandler<Ops> : Send + Sync + 'static
 where
     Ops : Send + Sync + 'static
 {
-    async fn handle_request(self : Arc<Self>, op : Ops, data : &[u8]) -> Result<Vec<u8>, RpcResponseError>;
+    /// Called when a peer connects, before any messages are processed.
+    /// Returning an error rejects the connection. This is the place to
+    /// check the peer address and to set up the session payload.
+    async fn connect(self : Arc<Self>, _ctx : Arc<RpcContext>) -> WebSocketResult<()> {
+        Ok(())
+    }
+
+    /// Called when the peer completes the RPC handshake, i.e. once a
+    /// [`CtlOp::Handshake`] request or a [`JSONRPC_HANDSHAKE_METHOD`] call
+    /// has been accepted. It runs after the [`RpcAuthenticator`], if one
+    /// is installed, has validated the credentials and the identity has
+    /// been attached to the context, and before the response is sent.
+    /// It is not called for challenge requests or failed attempts.
+    /// Returning an error rejects the handshake: the identity is removed
+    /// and the peer receives the error with [`RespStatus::Unauthorized`].
+    async fn handshake(self : Arc<Self>, _ctx : Arc<RpcContext>) -> Result<(), RpcResponseError> {
+        Ok(())
+    }
+
+    /// Called once the connection has been closed, allowing
+    /// the handler to release per-connection resources.
+    async fn disconnect(self : Arc<Self>, _ctx : Arc<RpcContext>) { }
+
+    async fn handle_request(self : Arc<Self>, ctx : Arc<RpcContext>, op : Ops, data : &[u8]) -> Result<Vec<u8>, RpcResponseError>;
+
+    /// Handle a request opened as a stream. Each item is sent to the
+    /// client in its own frame as the client grants credits for it.
+    /// Ops that do not support streaming produce an unknown op response.
+    async fn handle_stream_request(self : Arc<Self>, _ctx : Arc<RpcContext>, _op : Ops, _data : &[u8]) -> Result<RpcStream, RpcResponseError> {
+        Err(RpcResponseError::NotFound)
+    }
+
+    /// Called when the connection subscribes to `topic`. Returning
+    /// an error rejects the subscription.
+    async fn subscribe(self : Arc<Self>, _ctx : Arc<RpcContext>, _topic : &str) -> Result<(), RpcResponseError> {
+        Ok(())
+    }
+
+    /// Handle a request opened as an upload. `data` carries the initial
+    /// request and the uploaded content arrives through `upload`.
+    /// Ops that do not accept uploads produce an unknown op response.
+    async fn handle_upload(self : Arc<Self>, _ctx : Arc<RpcContext>, _op : Ops, _data : &[u8], _upload : RpcUpload) -> Result<Vec<u8>, RpcResponseError> {
+        Err(RpcResponseError::NotFound)
+    }
+}
+
+/// Request execution mode applied to each connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RpcConcurrency {
+    /// Requests are processed one at a time in the order received.
+    Sequential,
+    /// Requests are dispatched concurrently. Once `max_in_flight`
+    /// requests are executing, further requests wait for one of them
+    /// to complete. Streams and uploads are not counted, as they stay
+    /// open for as long as the client keeps them running.
+    Concurrent { max_in_flight : usize },
+}
+
+impl Default for RpcConcurrency {
+    fn default() -> Self {
+        RpcConcurrency::Concurrent { max_in_flight : 64 }
+    }
+}
+
+/// Options controlling the behavior of the [`RpcServer`].
+#[derive(Clone, Default)]
+pub struct RpcServerOptions {
+    /// Credential validation performed during the handshake.
+    /// When set, connections must complete the handshake
+    /// before any requests are processed. JSON-RPC peers perform
+    /// the handshake by calling [`JSONRPC_HANDSHAKE_METHOD`].
+    pub authenticator : Option<Arc<dyn RpcAuthenticator>>,
+    /// Per-connection request execution mode.
+    pub concurrency : RpcConcurrency,
+    /// JSON-RPC 2.0 handler processing text frames. When not
+    /// set, text frames are ignored.
+    pub json_handler : Option<Arc<dyn RpcHandlerSerde>>,
+    /// Limits applied to client uploads.
+    pub uploads : RpcUploadLimits,
+}
+
+/// Limits applied to each client upload.
+#[derive(Clone, Copy, Debug)]
+pub struct RpcUploadLimits {
+    /// Total number of bytes accepted for a single upload. Uploads
+    /// exceeding it are aborted with [`RpcResponseError::PayloadTooLarge`].
+    pub max_size : usize,
+    /// Number of chunks the client may send ahead of the handler.
+    pub window : u32,
+}
+
+impl Default for RpcUploadLimits {
+    fn default() -> Self {
+        RpcUploadLimits {
+            max_size : 64 * 1024 * 1024,
+            window : DEFAULT_UPLOAD_WINDOW,
+        }
+    }
+}
+
+/// Post a response frame to the peer. Responses share the context
+/// queue with notifications, publications and the close frame, so the
+/// peer receives them in the order they were produced.
+pub(crate) fn respond(ctx : &RpcContext, id : u64, status : RespStatus, data : &[u8]) {
+    if let Ok(msg) = RespMessage::new(id, status as u32, data).try_to_vec() {
+        match ctx.post(msg) {
+            Ok(_) => {},
+            Err(e) => { log_trace!("Sink error: {:?}", e); }
+        }
+    }
+}
+
+fn respond_with_error(ctx : &RpcContext, id : u64, status : RespStatus, err : RpcResponseError) {
+    if let Ok(err_vec) = err.try_to_vec() {
+        respond(ctx, id, status, &err_vec);
+    }
+}
+
+fn respond_with_result(ctx : &RpcContext, id : u64, result : Result<Vec<u8>, RpcResponseError>) {
+    match result {
+        Ok(data) => {
+            respond(ctx, id, RespStatus::Success, &data);
+        },
+        Err(RpcResponseError::NotFound) => {
+            respond(ctx, id, RespStatus::UnknownOp, &[]);
+        },
+        Err(err) => {
+            log_trace!("RPC server error: {:?}", err);
+            respond_with_error(ctx, id, RespStatus::Error, err);
+        }
+    }
+}
+
+/// Request dispatched to the handler on behalf of a connection.
+struct RpcCall<Ops> {
+    ctx : Arc<RpcContext>,
+    id : u64,
+    /// Wire value of `op`, used to record metrics
+    op_id : u32,
+    op : Ops,
 }
 
 #[derive(Clone)]
@@ -37,16 +191,336 @@ pub struct RpcWebSocketHandler<Ops>
 where
     Ops: Send + Sync + TryFrom<u32> + 'static
 {
-    rpc_handler : Arc<dyn RpcHandler<Ops>>
+    rpc_handler : Arc<dyn RpcHandler<Ops>>,
+    options : RpcServerOptions,
+    metrics : Arc<RpcMetrics>,
+    topics : Arc<RpcTopics>,
+    connections : Arc<RpcConnections>,
 }
 
 impl<Ops> RpcWebSocketHandler<Ops>
 where
     Ops: Send + Sync + TryFrom<u32> + 'static
 {
-    pub fn new(rpc_handler : Arc<dyn RpcHandler<Ops>>) -> Self {
+    pub fn new(rpc_handler : Arc<dyn RpcHandler<Ops>>, options : RpcServerOptions) -> Self {
+        let topics = Arc::new(RpcTopics::default());
         Self {
-            rpc_handler
+            rpc_handler,
+            options,
+            metrics : Arc::new(RpcMetrics::default()),
+            topics : topics.clone(),
+            connections : Arc::new(RpcConnections::new(topics)),
+        }
+    }
+
+    /// Run the request once its turn has come. Waiting for the turn
+    /// here rather than on the connection keeps cancellations and stream
+    /// acknowledgements flowing while earlier requests are executing.
+    async fn dispatch(&self, call : RpcCall<Ops>, data : &[u8], registration : AbortRegistration, mut turn : Turn) {
+        let RpcCall { ctx, id, op_id, op } = call;
+        self.metrics.begin(op_id, data.len());
+        let request = async {
+            turn.wait().await;
+            let ts = Instant::now();
+            let result = self.rpc_handler.clone().handle_request(ctx.clone(),op,data).await;
+            (ts, result)
+        };
+        let (ts, result) = match Abortable::new(request, registration).await {
+            Ok(result) => result,
+            Err(_) => {
+                log_trace!("RPC request {} cancelled", id);
+                self.metrics.cancel(op_id);
+                return;
+            }
+        };
+        self.metrics.end(op_id, ts.elapsed(), &result);
+        respond_with_result(&ctx, id, result);
+    }
+
+    async fn handle_subscription(&self, ctx : &Arc<RpcContext>, id : u64, subscribe : bool, data : &[u8]) {
+        let topic = match String::try_from_slice(data) {
+            Ok(topic) => topic,
+            Err(_) => {
+                respond_with_error(ctx, id, RespStatus::Error, RpcResponseError::ReqDeserialize);
+                return;
+            }
+        };
+
+        if !subscribe {
+            self.topics.unsubscribe(&topic, ctx);
+            respond(ctx, id, RespStatus::Success, &[]);
+            return;
+        }
+
+        match self.rpc_handler.clone().subscribe(ctx.clone(), &topic).await {
+            Ok(_) => {
+                self.topics.subscribe(&topic, ctx);
+                respond(ctx, id, RespStatus::Success, &[]);
+            },
+            Err(err) => {
+                log_trace!("RPC subscription to {} rejected for {}: {:?}", topic, ctx.peer, err);
+                respond_with_error(ctx, id, RespStatus::Error, err);
+            }
+        }
+    }
+
+    /// Open an upload. Like streams, uploads always run in their own
+    /// task so that chunks can be read from the connection while the
+    /// handler is processing them, and are not counted against the
+    /// in-flight request limit.
+    async fn handle_upload(self : &Arc<Self>, ctx : &Arc<RpcContext>, id : u64, data : &[u8]) {
+        let request = match UploadRequest::try_from_slice(data) {
+            Ok(request) => request,
+            Err(_) => {
+                respond_with_error(ctx, id, RespStatus::Error, RpcResponseError::ReqDeserialize);
+                return;
+            }
+        };
+
+        let op = match Ops::try_from(request.op) {
+            Ok(op) => op,
+            Err(_) => {
+                log_trace!("RPC unknown upload opcode {} from {}", request.op, ctx.peer);
+                respond(ctx, id, RespStatus::UnknownOp, &[]);
+                return;
+            }
+        };
+
+        let window = self.options.uploads.window.max(1);
+        let (sender, receiver) = tokio::sync::mpsc::channel(window as usize);
+        ctx.register_upload(id, request.op, sender);
+        let (handle, registration) = AbortHandle::new_pair();
+        ctx.register_request(id, handle);
+        self.metrics.begin(request.op, request.data.len());
+
+        let upload = RpcUpload::new(id, receiver, ctx.clone(), window);
+        if let Ok(data) = window.try_to_vec() {
+            respond(ctx, id, RespStatus::UploadAck, &data);
+        }
+
+        let this = self.clone();
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            let op_id = request.op;
+            let ts = Instant::now();
+            let task = this.rpc_handler.clone().handle_upload(ctx.clone(), op, &request.data, upload);
+            match Abortable::new(task, registration).await {
+                Ok(result) => {
+                    this.metrics.end(op_id, ts.elapsed(), &result);
+                    respond_with_result(&ctx, id, result);
+                },
+                Err(_) => {
+                    log_trace!("RPC upload {} cancelled", id);
+                    this.metrics.cancel(op_id);
+                }
+            }
+            ctx.unregister_request(id);
+            ctx.unregister_upload(id);
+        });
+    }
+
+    /// Open a response stream. Streams always run in their own
+    /// task so that acknowledgements can be read from the connection
+    /// while items are being produced, including in sequential mode.
+    /// They are not counted against the in-flight request limit.
+    async fn handle_stream(self : &Arc<Self>, ctx : &Arc<RpcContext>, id : u64, data : &[u8]) {
+        let request = match StreamRequest::try_from_slice(data) {
+            Ok(request) => request,
+            Err(_) => {
+                respond_with_error(ctx, id, RespStatus::Error, RpcResponseError::ReqDeserialize);
+                return;
+            }
+        };
+
+        let op = match Ops::try_from(request.op) {
+            Ok(op) => op,
+            Err(_) => {
+                log_trace!("RPC unknown stream opcode {} from {}", request.op, ctx.peer);
+                respond(ctx, id, RespStatus::UnknownOp, &[]);
+                return;
+            }
+        };
+
+        let credits = Arc::new(Semaphore::new(request.window.max(1) as usize));
+        ctx.register_stream(id, credits.clone());
+        let (handle, registration) = AbortHandle::new_pair();
+        ctx.register_request(id, handle);
+        self.metrics.begin(request.op, request.data.len());
+
+        let this = self.clone();
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            let op_id = request.op;
+            let call = RpcCall { ctx : ctx.clone(), id, op_id, op };
+            let task = this.stream(call, request.data, credits);
+            if Abortable::new(task, registration).await.is_err() {
+                log_trace!("RPC stream {} cancelled", id);
+                this.metrics.cancel(op_id);
+            }
+            ctx.unregister_request(id);
+            ctx.unregister_stream(id);
+        });
+    }
+
+    async fn stream(&self, call : RpcCall<Ops>, data : Vec<u8>, credits : Arc<Semaphore>) {
+        let RpcCall { ctx, id, op_id, op } = call;
+        let ts = Instant::now();
+        let mut stream = match self.rpc_handler.clone().handle_stream_request(ctx.clone(), op, &data).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                self.metrics.end_with(op_id, ts.elapsed(), 0, Some(&err));
+                match err {
+                    RpcResponseError::NotFound => respond(&ctx, id, RespStatus::UnknownOp, &[]),
+                    err => respond_with_error(&ctx, id, RespStatus::Error, err),
+                }
+                return;
+            }
+        };
+
+        let mut bytes_sent = 0;
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(data) => {
+                    match credits.acquire().await {
+                        Ok(permit) => permit.forget(),
+                        Err(_) => { return; }
+                    }
+                    bytes_sent += data.len();
+                    respond(&ctx, id, RespStatus::StreamItem, &data);
+                },
+                Err(err) => {
+                    self.metrics.end_with(op_id, ts.elapsed(), bytes_sent, Some(&err));
+                    respond_with_error(&ctx, id, RespStatus::Error, err);
+                    return;
+                }
+            }
+        }
+
+        self.metrics.end_with(op_id, ts.elapsed(), bytes_sent, None);
+        respond(&ctx, id, RespStatus::StreamEnd, &[]);
+    }
+
+    async fn handle_json(json_handler : Arc<dyn RpcHandlerSerde>, ctx : Arc<RpcContext>, text : String) {
+        if let Some(response) = dispatch_json(json_handler, ctx.clone(), &text).await {
+            match ctx.post_message(Message::Text(response)) {
+                Ok(_) => {},
+                Err(e) => { log_trace!("Sink error: {:?}", e); }
+            }
+        }
+    }
+
+    async fn handle_text(&self, ctx : &Arc<RpcContext>, text : String) {
+        let mut json_handler = match &self.options.json_handler {
+            Some(json_handler) => json_handler.clone(),
+            None => { return; }
+        };
+
+        if let Some(response) = self.handle_json_handshake(ctx, &text).await {
+            if let Ok(response) = serde_json::to_string(&response) {
+                let _ = ctx.post_message(Message::Text(response));
+            }
+            return;
+        }
+
+        if check_handshake(self.options.authenticator.is_some(), ctx.is_authenticated()).is_err() {
+            json_handler = Arc::new(RejectUnauthenticated);
+        }
+
+        let mut turn = ctx.turn(self.options.concurrency == RpcConcurrency::Sequential);
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            turn.wait().await;
+            Self::handle_json(json_handler, ctx, text).await;
+        });
+    }
+
+    /// Process a single JSON-RPC request calling [`JSONRPC_HANDSHAKE_METHOD`].
+    /// Returns `None` if `text` is not such a request.
+    async fn handle_json_handshake(&self, ctx : &Arc<RpcContext>, text : &str) -> Option<JsonRpcResponse> {
+        if !text.contains(JSONRPC_HANDSHAKE_METHOD) {
+            return None;
+        }
+        let request = serde_json::from_str::<JsonRpcRequest>(text).ok()?;
+        if request.method != JSONRPC_HANDSHAKE_METHOD {
+            return None;
+        }
+
+        let id = request.id.unwrap_or(serde_json::Value::Null);
+        let handshake = match serde_json::from_value::<HandshakeRequest>(request.params) {
+            Ok(handshake) => handshake,
+            Err(err) => {
+                return Some(JsonRpcResponse::error(id, JsonRpcError::invalid_params(&err.to_string())));
+            }
+        };
+
+        let result = match HandshakeStep::resolve(handshake, self.options.authenticator.is_some(), || ctx.take_challenge()) {
+            Ok(step) => self.authenticate(ctx, step).await,
+            Err(err) => Err(err),
+        };
+        match self.accept(ctx, result).await {
+            Ok(response) => {
+                match serde_json::to_value(&response) {
+                    Ok(result) => Some(JsonRpcResponse::result(id, result)),
+                    Err(err) => Some(JsonRpcResponse::error(id, JsonRpcError::internal_error(&err.to_string()))),
+                }
+            },
+            Err(err) => {
+                log_trace!("RPC handshake failure for {}: {:?}", ctx.peer, err);
+                Some(JsonRpcResponse::error(id, JsonRpcError::unauthorized().with_data(serde_json::Value::String(err.kind().to_string()))))
+            }
+        }
+    }
+
+    async fn handle_handshake(&self, ctx : &Arc<RpcContext>, id : u64, data : &[u8]) {
+        let result = match HandshakeStep::decode(data, self.options.authenticator.is_some(), || ctx.take_challenge()) {
+            Ok(step) => self.authenticate(ctx, step).await,
+            Err(err) => Err(err),
+        };
+
+        match self.accept(ctx, result).await {
+            Ok(response) => {
+                if let Ok(data) = response.try_to_vec() {
+                    respond(ctx, id, RespStatus::Success, &data);
+                }
+            },
+            Err(err) => {
+                log_trace!("RPC handshake failure for {}: {:?}", ctx.peer, err);
+                respond_with_error(ctx, id, RespStatus::Unauthorized, err);
+            }
+        }
+    }
+
+    /// Attach the identity accepted by the handshake to the connection
+    /// and run the [`RpcHandler::handshake`] hook, which may still
+    /// reject it. Other outcomes are passed through unchanged.
+    async fn accept(&self, ctx : &Arc<RpcContext>, result : Result<HandshakeResponse, RpcResponseError>) -> Result<HandshakeResponse, RpcResponseError> {
+        if let Ok(HandshakeResponse::Accepted(identity)) = &result {
+            ctx.set_identity(identity.clone());
+            if let Err(err) = self.rpc_handler.clone().handshake(ctx.clone()).await {
+                ctx.clear_identity();
+                return Err(err);
+            }
+        }
+        result
+    }
+
+    /// Perform a handshake step with the authenticator.
+    async fn authenticate(&self, ctx : &Arc<RpcContext>, step : HandshakeStep) -> Result<HandshakeResponse, RpcResponseError> {
+        match (step, &self.options.authenticator) {
+            (HandshakeStep::Challenge, Some(authenticator)) => {
+                authenticator.clone().challenge(ctx.clone()).await
+                    .map(|challenge| {
+                        ctx.set_challenge(challenge.clone());
+                        HandshakeResponse::Challenge(challenge)
+                    })
+            },
+            (HandshakeStep::Authenticate(credentials), Some(authenticator)) => {
+                authenticator.clone().authenticate(ctx.clone(), credentials).await
+                    .map(HandshakeResponse::Accepted)
+            },
+            (HandshakeStep::Anonymous, _) | (_, None) => {
+                Ok(HandshakeResponse::Accepted(RpcIdentity::default()))
+            }
         }
     }
 }
@@ -60,51 +534,149 @@ where
     type Context = Arc<RpcContext>;
 
     async fn connect(self : &Arc<Self>, peer: SocketAddr) -> WebSocketResult<Self::Context> {
-        let ctx = RpcContext { peer };
-        Ok(Arc::new(ctx))
+        let max_in_flight = match self.options.concurrency {
+            RpcConcurrency::Sequential => 1,
+            RpcConcurrency::Concurrent { max_in_flight } => max_in_flight.max(1),
+        };
+        // the context can post to the peer from here on; frames are
+        // queued until the server hands over the connection sink
+        let ctx = Arc::new(RpcContext::new(peer, max_in_flight));
+        self.rpc_handler.clone().connect(ctx.clone()).await?;
+        self.connections.insert(&ctx);
+        Ok(ctx)
     }
 
-    async fn handshake(self : &Arc<Self>, _ctx : &Self::Context, _msg : Message, _sink : &UnboundedSender<tungstenite::Message>) -> WebSocketResult<()> {
-        Ok(())
+    /// The RPC handshake is carried in-band by [`CtlOp::Handshake`]
+    /// requests, so a WebSocket handshake phase is not used. Should the
+    /// WebSocket server perform one anyway, the frame it consumes is
+    /// processed like any other.
+    async fn handshake(self : &Arc<Self>, ctx : &Self::Context, msg : Message, sink : &UnboundedSender<tungstenite::Message>) -> WebSocketResult<()> {
+        self.message(ctx, msg, sink).await
+    }
+
+    async fn disconnect(self : &Arc<Self>, ctx : Self::Context, _result : WebSocketResult<()>) {
+        self.connections.remove(ctx.id);
+        ctx.cancel_all_requests();
+        self.topics.remove_connection(&ctx);
+        self.rpc_handler.clone().disconnect(ctx).await;
     }
 
-    async fn message(self : &Arc<Self>, _ctx : &Self::Context, msg : Message, sink : &UnboundedSender<tungstenite::Message>) -> WebSocketResult<()> {
+    async fn message(self : &Arc<Self>, ctx : &Self::Context, msg : Message, sink : &UnboundedSender<tungstenite::Message>) -> WebSocketResult<()> {
+
+        ctx.bind_sink(sink);
+        // closed by the server; the close frame queued by the context
+        // is on its way, including to a peer that had not sent anything
+        if ctx.is_closed() {
+            return Ok(());
+        }
+
+        if msg.is_text() {
+            if let Ok(text) = msg.into_text() {
+                self.handle_text(ctx, text).await;
+            }
+            return Ok(())
+        }
 
         if !msg.is_binary() {
             return Ok(())
         }
 
-        let data = &msg.into_data();
-        let req : ReqMessage = data.try_into().expect("invalid message!");
+        let data = msg.into_data();
+        let req : ReqMessage = match (&data).try_into() {
+            Ok(req) => req,
+            Err(_) => {
+                if data.len() >= size_of::<u64>() {
+                    let id = u64::from_ne_bytes(data[..size_of::<u64>()].try_into().unwrap());
+                    respond(ctx, id, RespStatus::MalformedHeader, &[]);
+                } else {
+                    log_trace!("RPC closing connection from {}: malformed frame of {} bytes", ctx.peer, data.len());
+                    if let Err(e) = ctx.close_with(CloseCode::Protocol, "malformed RPC frame") {
+                        log_trace!("Sink error: {:?}", e);
+                    }
+                }
+                return Ok(());
+            }
+        };
+
+        if req.op == CtlOp::Handshake as u32 {
+            self.handle_handshake(ctx, req.id, req.data).await;
+            return Ok(());
+        }
+
+        // requests run off the reader in either mode, so they can be
+        // aborted while waiting for their turn and while executing
+        if req.op == CtlOp::Cancel as u32 {
+            ctx.cancel_request(req.id);
+            return Ok(());
+        }
+
+        if req.op == CtlOp::StreamAck as u32 {
+            match u32::try_from_slice(req.data) {
+                Ok(credits) => ctx.stream_ack(req.id, credits),
+                Err(_) => { log_trace!("RPC malformed stream ack from {}", ctx.peer); }
+            }
+            return Ok(());
+        }
+
+        if let Err(err) = check_handshake(self.options.authenticator.is_some(), ctx.is_authenticated()) {
+            respond_with_error(ctx, req.id, RespStatus::Unauthorized, err);
+            return Ok(());
+        }
+
+        if req.op == CtlOp::Stream as u32 {
+            self.handle_stream(ctx, req.id, req.data).await;
+            return Ok(());
+        }
+
+        if req.op == CtlOp::Subscribe as u32 || req.op == CtlOp::Unsubscribe as u32 {
+            self.handle_subscription(ctx, req.id, req.op == CtlOp::Subscribe as u32, req.data).await;
+            return Ok(());
+        }
+
+        if req.op == CtlOp::Upload as u32 {
+            self.handle_upload(ctx, req.id, req.data).await;
+            return Ok(());
+        }
+
+        if req.op == CtlOp::UploadChunk as u32 {
+            match ctx.push_upload_chunk(req.id, req.data, self.options.uploads.max_size) {
+                Some(Ok(op)) => {
+                    self.metrics.received(op, req.data.len());
+                },
+                Some(Err(err)) => {
+                    ctx.cancel_request(req.id);
+                    respond_with_error(ctx, req.id, RespStatus::Error, err);
+                },
+                None => { }
+            }
+            return Ok(());
+        }
+
+        if req.op == CtlOp::UploadEnd as u32 {
+            ctx.unregister_upload(req.id);
+            return Ok(());
+        }
 
         let op = Ops::try_from(req.op); 
         match op {
             Ok(op) => {
-                let result = self.rpc_handler.clone().handle_request(op,req.data).await;
-                match result {
-                    Ok(data) => {
-                        if let Ok(msg) = RespMessage::new(req.id, 0, &data).try_to_vec() {
-                            match sink.send(msg.into()) {
-                                Ok(_) => {},
-                                Err(e) => { log_trace!("Sink error: {:?}", e); }
-                            }
-                        }
-                    },
-                    Err(err) => {
-                        log_trace!("RPC server error: {:?}", err);
-                        if let Ok(err_vec) = err.try_to_vec() {
-                            if let Ok(msg) = RespMessage::new(req.id, 1, &err_vec).try_to_vec() {
-                                match sink.send(msg.into()) {
-                                    Ok(_) => {},
-                                    Err(e) => { log_trace!("Sink error: {:?}", e); }
-                                }
-                            }
-                        }
-                    }
-                }
+                let id = req.id;
+                let op_id = req.op;
+                let turn = ctx.turn(self.options.concurrency == RpcConcurrency::Sequential);
+                let (handle, registration) = AbortHandle::new_pair();
+                ctx.register_request(id, handle);
+                let this = self.clone();
+                let ctx = ctx.clone();
+                tokio::spawn(async move {
+                    let payload = &data[size_of::<ReqHeader>()..];
+                    let call = RpcCall { ctx : ctx.clone(), id, op_id, op };
+                    this.dispatch(call, payload, registration, turn).await;
+                    ctx.unregister_request(id);
+                });
             },
             Err(_) => {
-                log_error!("invalid request opcode {}", req.op);                
+                log_trace!("RPC unknown request opcode {} from {}", req.op, ctx.peer);
+                respond(ctx, req.id, RespStatus::UnknownOp, &[]);
             }
         }
 
@@ -118,6 +690,9 @@ where
     <Ops as TryFrom<u32>>::Error: Sync + Send + 'static
 {
     ws_server : Arc<WebSocketServer<RpcWebSocketHandler<Ops>>>,
+    metrics : Arc<RpcMetrics>,
+    topics : Arc<RpcTopics>,
+    connections : Arc<RpcConnections>,
 }
 
 impl<Ops> RpcServer<Ops>
@@ -126,12 +701,275 @@ where
     <Ops as TryFrom<u32>>::Error: Sync + Send + 'static
 {
     pub fn new(rpc_handler : Arc<dyn RpcHandler<Ops>>) -> Arc<RpcServer<Ops>> {
-        let ws_handler = Arc::new(RpcWebSocketHandler::<Ops>::new(rpc_handler));
+        Self::new_with_options(rpc_handler, RpcServerOptions::default())
+    }
+
+    pub fn new_with_options(rpc_handler : Arc<dyn RpcHandler<Ops>>, options : RpcServerOptions) -> Arc<RpcServer<Ops>> {
+        let ws_handler = Arc::new(RpcWebSocketHandler::<Ops>::new(rpc_handler, options));
+        let metrics = ws_handler.metrics.clone();
+        let topics = ws_handler.topics.clone();
+        let connections = ws_handler.connections.clone();
         let ws_server = WebSocketServer::new(ws_handler);
-        Arc::new(RpcServer { ws_server })
+        Arc::new(RpcServer { ws_server, metrics, topics, connections })
+    }
+
+    /// Registry of active connections, used to address
+    /// individual peers and to broadcast notifications.
+    pub fn connections(&self) -> Arc<RpcConnections> {
+        self.connections.clone()
+    }
+
+    /// Topic subscription registry used to publish to subscribers.
+    pub fn topics(&self) -> Arc<RpcTopics> {
+        self.topics.clone()
+    }
+
+    /// Snapshot of the per-op request metrics, keyed by op.
+    pub fn metrics(&self) -> AHashMap<u32, RpcOpMetrics> {
+        self.metrics.snapshot()
+    }
+
+    pub fn reset_metrics(&self) {
+        self.metrics.reset();
     }
 
     pub async fn listen(self : &Arc<Self>, addr : &str) -> WebSocketResult<()> {
         Ok(self.ws_server.listen(addr).await?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum TestOps {
+        Echo = 0,
+        Notify = 1,
+        /// Streams the numbers below the requested count
+        Count = 2,
+        /// Sums the bytes uploaded
+        Sum = 3,
+        /// Never completes
+        Wait = 4,
+    }
+
+    impl TryFrom<u32> for TestOps {
+        type Error = ();
+        fn try_from(op : u32) -> Result<Self, Self::Error> {
+            match op {
+                0 => Ok(TestOps::Echo),
+                1 => Ok(TestOps::Notify),
+                2 => Ok(TestOps::Count),
+                3 => Ok(TestOps::Sum),
+                4 => Ok(TestOps::Wait),
+                _ => Err(()),
+            }
+        }
+    }
+
+    impl From<TestOps> for u32 {
+        fn from(op : TestOps) -> u32 {
+            op as u32
+        }
+    }
+
+    struct TestHandler;
+
+    #[async_trait]
+    impl RpcHandler<TestOps> for TestHandler {
+        async fn handle_request(self : Arc<Self>, ctx : Arc<RpcContext>, op : TestOps, data : &[u8]) -> Result<Vec<u8>, RpcResponseError> {
+            match op {
+                TestOps::Echo => Ok(data.to_vec()),
+                TestOps::Notify => {
+                    ctx.notify_with_buffer(op.into(), data).map_err(|err| RpcResponseError::Text(err.to_string()))?;
+                    Ok(vec![])
+                },
+                TestOps::Wait => futures::future::pending().await,
+                TestOps::Count | TestOps::Sum => Err(RpcResponseError::NotFound),
+            }
+        }
+
+        async fn handle_stream_request(self : Arc<Self>, _ctx : Arc<RpcContext>, op : TestOps, data : &[u8]) -> Result<RpcStream, RpcResponseError> {
+            if op != TestOps::Count {
+                return Err(RpcResponseError::NotFound);
+            }
+            let count = u32::try_from_slice(data).map_err(|_| RpcResponseError::ReqDeserialize)?;
+            Ok(futures::stream::iter((0..count).map(|item| Ok(item.try_to_vec().unwrap()))).boxed())
+        }
+
+        async fn handle_upload(self : Arc<Self>, _ctx : Arc<RpcContext>, op : TestOps, _data : &[u8], mut upload : RpcUpload) -> Result<Vec<u8>, RpcResponseError> {
+            if op != TestOps::Sum {
+                return Err(RpcResponseError::NotFound);
+            }
+            let mut sum = 0u32;
+            while let Some(chunk) = upload.recv().await {
+                sum += chunk.iter().map(|byte| *byte as u32).sum::<u32>();
+            }
+            Ok(sum.try_to_vec().unwrap())
+        }
+    }
+
+    /// Rejects the handshake unless `accept` is set.
+    struct HandshakeGate {
+        accept : bool,
+        calls : std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl RpcHandler<TestOps> for HandshakeGate {
+        async fn handshake(self : Arc<Self>, ctx : Arc<RpcContext>) -> Result<(), RpcResponseError> {
+            assert!(ctx.is_authenticated());
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if self.accept { Ok(()) } else { Err(RpcResponseError::Forbidden) }
+        }
+
+        async fn handle_request(self : Arc<Self>, _ctx : Arc<RpcContext>, _op : TestOps, data : &[u8]) -> Result<Vec<u8>, RpcResponseError> {
+            Ok(data.to_vec())
+        }
+    }
+
+    /// Connection driven through the [`WebSocketHandler`] the way the
+    /// WebSocket server drives it, collecting the frames sent to the peer.
+    struct Peer {
+        handler : Arc<RpcWebSocketHandler<TestOps>>,
+        ctx : Arc<RpcContext>,
+        sink : UnboundedSender<Message>,
+        frames : UnboundedReceiver<Message>,
+    }
+
+    impl Peer {
+        async fn connect(handler : Arc<dyn RpcHandler<TestOps>>, options : RpcServerOptions) -> Peer {
+            let handler = Arc::new(RpcWebSocketHandler::new(handler, options));
+            let ctx = handler.connect("127.0.0.1:1".parse().unwrap()).await.unwrap();
+            let (sink, frames) = unbounded_channel();
+            Peer { handler, ctx, sink, frames }
+        }
+
+        async fn send_message(&self, msg : Message) {
+            self.handler.message(&self.ctx, msg, &self.sink).await.unwrap();
+        }
+
+        async fn send(&self, id : u64, op : u32, data : &[u8]) {
+            let frame = to_vec((ReqHeader { id, op }, crate::asynchronous::message::Message::Request(data)));
+            self.send_message(Message::Binary(frame)).await;
+        }
+
+        async fn recv_message(&mut self) -> Message {
+            tokio::time::timeout(Duration::from_secs(5), self.frames.recv()).await
+                .expect("no frame received")
+                .expect("connection sink closed")
+        }
+
+        /// Next frame as `(id, status, payload)`.
+        async fn recv(&mut self) -> (u64, u32, Vec<u8>) {
+            let data = self.recv_message().await.into_data();
+            let resp = RespMessage::try_from(&data[..]).unwrap();
+            (resp.id, resp.status, resp.data.to_vec())
+        }
+    }
+
+    #[tokio::test]
+    async fn notifications_posted_by_a_handler_precede_its_response() {
+        let mut peer = Peer::connect(Arc::new(TestHandler), RpcServerOptions::default()).await;
+        peer.send(1, TestOps::Notify.into(), &[7]).await;
+        assert_eq!(peer.recv().await, (TestOps::Notify as u64, RespStatus::Notification as u32, vec![7]));
+        assert_eq!(peer.recv().await, (1, RespStatus::Success as u32, vec![]));
+    }
+
+    #[tokio::test]
+    async fn close_does_not_overtake_queued_responses() {
+        let mut peer = Peer::connect(Arc::new(TestHandler), RpcServerOptions::default()).await;
+        peer.send(1, TestOps::Echo.into(), &[1]).await;
+        assert_eq!(peer.recv().await, (1, RespStatus::Success as u32, vec![1]));
+
+        respond(&peer.ctx, 2, RespStatus::Success, &[2]);
+        peer.ctx.close("bye").unwrap();
+        assert_eq!(peer.recv().await, (2, RespStatus::Success as u32, vec![2]));
+        assert!(matches!(peer.recv_message().await, Message::Close(Some(frame)) if frame.reason == "bye"));
+    }
+
+    fn concurrent(max_in_flight : usize) -> RpcServerOptions {
+        RpcServerOptions { concurrency : RpcConcurrency::Concurrent { max_in_flight }, ..Default::default() }
+    }
+
+    fn item(item : u32) -> Vec<u8> {
+        item.try_to_vec().unwrap()
+    }
+
+    #[tokio::test]
+    async fn calls_proceed_while_a_stream_is_open() {
+        let mut peer = Peer::connect(Arc::new(TestHandler), concurrent(1)).await;
+        let request = StreamRequest { op : TestOps::Count.into(), window : 1, data : item(3) };
+        peer.send(1, CtlOp::Stream as u32, &request.try_to_vec().unwrap()).await;
+        assert_eq!(peer.recv().await, (1, RespStatus::StreamItem as u32, item(0)));
+
+        // the stream waits for credits while the call is processed
+        peer.send(2, TestOps::Echo.into(), &[2]).await;
+        assert_eq!(peer.recv().await, (2, RespStatus::Success as u32, vec![2]));
+
+        peer.send(1, CtlOp::StreamAck as u32, &item(2)).await;
+        assert_eq!(peer.recv().await, (1, RespStatus::StreamItem as u32, item(1)));
+        assert_eq!(peer.recv().await, (1, RespStatus::StreamItem as u32, item(2)));
+        assert_eq!(peer.recv().await, (1, RespStatus::StreamEnd as u32, vec![]));
+    }
+
+    #[tokio::test]
+    async fn calls_proceed_while_an_upload_is_open() {
+        let mut peer = Peer::connect(Arc::new(TestHandler), concurrent(1)).await;
+        let request = UploadRequest { op : TestOps::Sum.into(), data : vec![] };
+        peer.send(1, CtlOp::Upload as u32, &request.try_to_vec().unwrap()).await;
+        assert_eq!(peer.recv().await.1, RespStatus::UploadAck as u32);
+        peer.send(1, CtlOp::UploadChunk as u32, &[1, 2]).await;
+
+        // the upload waits for more chunks while the call is processed
+        peer.send(2, TestOps::Echo.into(), &[2]).await;
+        assert_eq!(peer.recv().await, (2, RespStatus::Success as u32, vec![2]));
+
+        peer.send(1, CtlOp::UploadChunk as u32, &[3]).await;
+        peer.send(1, CtlOp::UploadEnd as u32, &[]).await;
+        assert_eq!(peer.recv().await, (1, RespStatus::Success as u32, item(6)));
+    }
+
+    #[tokio::test]
+    async fn sequential_calls_can_be_cancelled() {
+        let options = RpcServerOptions { concurrency : RpcConcurrency::Sequential, ..Default::default() };
+        let mut peer = Peer::connect(Arc::new(TestHandler), options).await;
+        peer.send(1, TestOps::Wait.into(), &[]).await;
+        peer.send(2, TestOps::Echo.into(), &[2]).await;
+        peer.send(3, TestOps::Echo.into(), &[3]).await;
+
+        // the queued calls run in order once the running one is aborted
+        peer.send(1, CtlOp::Cancel as u32, &[]).await;
+        assert_eq!(peer.recv().await, (2, RespStatus::Success as u32, vec![2]));
+        assert_eq!(peer.recv().await, (3, RespStatus::Success as u32, vec![3]));
+    }
+
+    #[tokio::test]
+    async fn handshake_hook_runs_for_accepted_handshakes() {
+        let handler = Arc::new(HandshakeGate { accept : true, calls : Default::default() });
+        let mut peer = Peer::connect(handler.clone(), RpcServerOptions::default()).await;
+        let request = HandshakeRequest::Token("secret".to_string()).try_to_vec().unwrap();
+        peer.send(1, CtlOp::Handshake as u32, &request).await;
+        let (id, status, data) = peer.recv().await;
+        assert_eq!((id, status), (1, RespStatus::Success as u32));
+        assert!(matches!(HandshakeResponse::try_from_slice(&data).unwrap(), HandshakeResponse::Accepted(_)));
+        assert_eq!(handler.calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(peer.ctx.is_authenticated());
+    }
+
+    #[tokio::test]
+    async fn handshake_hook_can_reject_the_handshake() {
+        let handler = Arc::new(HandshakeGate { accept : false, calls : Default::default() });
+        let mut peer = Peer::connect(handler.clone(), RpcServerOptions::default()).await;
+        let request = HandshakeRequest::Token("secret".to_string()).try_to_vec().unwrap();
+        peer.send(1, CtlOp::Handshake as u32, &request).await;
+        let (id, status, data) = peer.recv().await;
+        assert_eq!((id, status), (1, RespStatus::Unauthorized as u32));
+        assert_eq!(RpcResponseError::try_from_slice(&data).unwrap().kind(), "Forbidden");
+        assert_eq!(handler.calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(!peer.ctx.is_authenticated());
+    }
+}
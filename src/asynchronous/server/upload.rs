@@ -0,0 +1,93 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use borsh::BorshSerialize;
+use futures::stream::Stream;
+use tokio::sync::mpsc::Receiver;
+use crate::asynchronous::message::*;
+use super::context::RpcContext;
+use super::server::respond;
+
+/// Chunks of a client upload received by
+/// [`RpcHandler::handle_upload`](super::RpcHandler::handle_upload).
+/// Consumed chunks are acknowledged to the client in batches of half
+/// the upload window, so the client never sends more than one window
+/// ahead of the handler. The stream ends once the client has sent
+/// its last chunk.
+pub struct RpcUpload {
+    id : u64,
+    receiver : Receiver<Vec<u8>>,
+    ctx : Arc<RpcContext>,
+    window : u32,
+    consumed : u32,
+    received : usize,
+    limits : Vec<(usize, Arc<AtomicBool>)>,
+}
+
+impl RpcUpload {
+    pub(crate) fn new(id : u64, receiver : Receiver<Vec<u8>>, ctx : Arc<RpcContext>, window : u32) -> Self {
+        RpcUpload {
+            id,
+            receiver,
+            ctx,
+            window,
+            consumed : 0,
+            received : 0,
+            limits : Vec::new(),
+        }
+    }
+
+    /// Receive the next chunk; `None` once the upload is complete.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        futures::StreamExt::next(self).await
+    }
+
+    /// End the upload once more than `max_size` bytes have been
+    /// received. The returned flag is raised if the limit was hit.
+    pub(crate) fn limit(&mut self, max_size : usize) -> Arc<AtomicBool> {
+        let exceeded = Arc::new(AtomicBool::new(false));
+        self.limits.push((max_size, exceeded.clone()));
+        exceeded
+    }
+
+    fn ack(&mut self) {
+        if let Ok(data) = self.consumed.try_to_vec() {
+            respond(&self.ctx, self.id, RespStatus::UploadAck, &data);
+        }
+        self.consumed = 0;
+    }
+}
+
+impl Stream for RpcUpload {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self : Pin<&mut Self>, cx : &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.limits.iter().any(|(_, exceeded)| exceeded.load(Ordering::SeqCst)) {
+            return Poll::Ready(None);
+        }
+
+        match self.receiver.poll_recv(cx) {
+            Poll::Ready(Some(chunk)) => {
+                self.received += chunk.len();
+                let received = self.received;
+                let mut exceeded = false;
+                for (_, flag) in self.limits.iter().filter(|(max_size, _)| received > *max_size) {
+                    flag.store(true, Ordering::SeqCst);
+                    exceeded = true;
+                }
+                if exceeded {
+                    self.receiver.close();
+                    return Poll::Ready(None);
+                }
+                self.consumed += 1;
+                if self.consumed >= (self.window / 2).max(1) {
+                    self.ack();
+                }
+                Poll::Ready(Some(chunk))
+            },
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
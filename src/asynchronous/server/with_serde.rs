@@ -1,66 +1,200 @@
-// use std::fmt::Debug;
-// use std::sync::Arc;
-// use async_trait::async_trait;
-// use serde::{Serialize,Deserialize};
-// use crate::asynchronous::server::RpcHandler;
-// use crate::asynchronous::ops::RpcOps;
-// use crate::asynchronous::error::RpcResponseError;
-
-// #[async_trait]
-// pub trait RpcHandlerSerde //<Req,Resp> : Send + Sync + 'static
-// // where
-// //     Req : Send + Sync + 'static,
-// //     Resp : Send + Sync + 'static,
-// {
-//     async fn handle_request(self : Arc<Self>, request : Req) -> Result<Resp, RpcResponseError>;
-// }
-
-// #[derive(Clone)]
-// pub struct RpcHandlerSerdeAdaptor<Req,Resp> 
-// // where 
-// //     Req : Send + Sync + 'static,
-// //     Resp : Send + Sync + 'static,
-// {
-//     handler : Arc<dyn RpcHandlerSerde<Req,Resp>>,
-// }
-
-// impl<Req,Resp> RpcHandlerSerdeAdaptor<Req,Resp>
-// // where
-// //     Req : Send + Sync + 'static,
-// //     Resp : Send + Sync + 'static,
-// {
-//     pub fn new(handler: Arc<dyn RpcHandlerSerde<Req,Resp>>) -> Self {
-//         Self { handler }
-//     }
-// }
-
-
-// #[async_trait]
-// impl<Req,Resp> RpcHandler<RpcOps> for RpcHandlerSerdeAdaptor<Req,Resp> 
-// where 
-//     // Req : Send + Sync + 'static,
-//     // Resp : Send + Sync + 'static,
-// {
-//     async fn handle_request(self : Arc<Self>, ops: RpcOps, data: Option<&[u8]>) -> Result<Resp, RpcResponseError> {
-
-//         // match data {
-//         //     Some(data) if ops == RpcOps::Serde => {
-//         //         let req = Req::try_from_slice(data).map_err(|_| { RpcResponseError::ReqDeserialize})?;
-
-//         //         let response = self.handler.clone().handle_request(req).await;
-//         //         match response {
-//         //             Ok(data) => Ok(Some(data.try_to_vec().map_err(|_|{ RpcResponseError::RespSerialize })?)),
-//         //             Err(err) => Err(err),
-//         //         }
-//         //     },
-//         //     None => {
-//         //         Err(RpcResponseError::NoData)
-//         //     },
-//         //     _ => {
-//         //         Err(RpcResponseError::NonSerdeRequest)
-//         //     }
-//         // }
-
-//         Ok(None)
-//     }
-// }
+use std::sync::Arc;
+use async_trait::async_trait;
+use futures::future::join_all;
+use serde_json::Value;
+use crate::asynchronous::jsonrpc::*;
+use super::context::RpcContext;
+
+/// Handler for JSON-RPC 2.0 requests received as WebSocket text
+/// frames. Installed via [`RpcServerOptions::json_handler`](super::RpcServerOptions)
+/// next to the binary [`RpcHandler`](super::RpcHandler).
+#[async_trait]
+pub trait RpcHandlerSerde : Send + Sync + 'static {
+    /// Process a single request or notification. `params` is
+    /// [`Value::Null`] when the request carries no parameters.
+    /// Results returned for notifications are discarded.
+    async fn handle_json_request(self : Arc<Self>, ctx : Arc<RpcContext>, method : &str, params : Value) -> Result<Value, JsonRpcError>;
+}
+
+/// Rejects every request of a connection that has not completed the
+/// handshake. Passed to [`dispatch_json`] in place of the installed
+/// handler, so that each request is answered under its own id.
+pub(crate) struct RejectUnauthenticated;
+
+#[async_trait]
+impl RpcHandlerSerde for RejectUnauthenticated {
+    async fn handle_json_request(self : Arc<Self>, _ctx : Arc<RpcContext>, _method : &str, _params : Value) -> Result<Value, JsonRpcError> {
+        Err(JsonRpcError::unauthorized())
+    }
+}
+
+/// Process a text frame containing a single JSON-RPC request or
+/// a batch. Returns the serialized response, or `None` if the
+/// frame consisted only of notifications.
+pub(crate) async fn dispatch_json(handler : Arc<dyn RpcHandlerSerde>, ctx : Arc<RpcContext>, text : &str) -> Option<String> {
+    let value : Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(_) => {
+            return serde_json::to_string(&JsonRpcResponse::error(Value::Null, JsonRpcError::parse_error())).ok();
+        }
+    };
+
+    match value {
+        Value::Array(batch) if batch.is_empty() => {
+            serde_json::to_string(&JsonRpcResponse::error(Value::Null, JsonRpcError::invalid_request())).ok()
+        },
+        Value::Array(batch) => {
+            let responses = join_all(batch.into_iter().map(|request| dispatch_one(handler.clone(), ctx.clone(), request))).await;
+            let responses = responses.into_iter().flatten().collect::<Vec<_>>();
+            if responses.is_empty() {
+                None
+            } else {
+                serde_json::to_string(&responses).ok()
+            }
+        },
+        request => {
+            let response = dispatch_one(handler, ctx, request).await?;
+            serde_json::to_string(&response).ok()
+        }
+    }
+}
+
+async fn dispatch_one(handler : Arc<dyn RpcHandlerSerde>, ctx : Arc<RpcContext>, request : Value) -> Option<JsonRpcResponse> {
+    let mut request = match request {
+        Value::Object(request) => request,
+        _ => {
+            return Some(JsonRpcResponse::error(Value::Null, JsonRpcError::invalid_request()));
+        }
+    };
+
+    let id = request.remove("id");
+    let id_is_valid = matches!(id, None | Some(Value::Null) | Some(Value::String(_)) | Some(Value::Number(_)));
+    let response_id = match &id {
+        Some(id) if id_is_valid => id.clone(),
+        _ => Value::Null,
+    };
+
+    let version_is_valid = request.get("jsonrpc").and_then(Value::as_str) == Some(JSONRPC_VERSION);
+    let method = match request.remove("method") {
+        Some(Value::String(method)) => Some(method),
+        _ => None,
+    };
+    let params = match request.remove("params") {
+        None => Some(Value::Null),
+        Some(params @ (Value::Array(_) | Value::Object(_))) => Some(params),
+        Some(_) => None,
+    };
+
+    let (method, params) = match (method, params) {
+        (Some(method), Some(params)) if version_is_valid && id_is_valid => (method, params),
+        _ => {
+            return Some(JsonRpcResponse::error(response_id, JsonRpcError::invalid_request()));
+        }
+    };
+
+    let result = handler.handle_json_request(ctx, &method, params).await;
+    let id = id?;
+    match result {
+        Ok(result) => Some(JsonRpcResponse::result(id, result)),
+        Err(err) => Some(JsonRpcResponse::error(id, err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    struct Calculator;
+
+    #[async_trait]
+    impl RpcHandlerSerde for Calculator {
+        async fn handle_json_request(self : Arc<Self>, _ctx : Arc<RpcContext>, method : &str, params : Value) -> Result<Value, JsonRpcError> {
+            match method {
+                "add" => {
+                    let (a, b) : (i64, i64) = serde_json::from_value(params)
+                        .map_err(|err| JsonRpcError::invalid_params(&err.to_string()))?;
+                    Ok(json!(a + b))
+                },
+                _ => Err(JsonRpcError::method_not_found(method)),
+            }
+        }
+    }
+
+    fn ctx() -> Arc<RpcContext> {
+        Arc::new(RpcContext::new("127.0.0.1:1".parse().unwrap(), 1))
+    }
+
+    async fn dispatch(handler : Arc<dyn RpcHandlerSerde>, text : &str) -> Option<Value> {
+        dispatch_json(handler, ctx(), text).await.map(|response| serde_json::from_str(&response).unwrap())
+    }
+
+    fn error_code(response : &Value) -> i64 {
+        response["error"]["code"].as_i64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn single_request_is_answered_under_its_id() {
+        let response = dispatch(Arc::new(Calculator), r#"{"jsonrpc":"2.0","method":"add","params":[1,2],"id":"a"}"#).await.unwrap();
+        assert_eq!(response, json!({ "jsonrpc" : "2.0", "result" : 3, "id" : "a" }));
+    }
+
+    #[tokio::test]
+    async fn batch_entries_are_answered_individually() {
+        let text = r#"[
+            {"jsonrpc":"2.0","method":"add","params":[1,2],"id":1},
+            {"jsonrpc":"2.0","method":"add","params":[3,4]},
+            {"jsonrpc":"2.0","method":"sub","params":[3,4],"id":2},
+            {"jsonrpc":"1.0","method":"add","id":3}
+        ]"#;
+        let response = dispatch(Arc::new(Calculator), text).await.unwrap();
+        let responses = response.as_array().unwrap();
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0], json!({ "jsonrpc" : "2.0", "result" : 3, "id" : 1 }));
+        assert_eq!((error_code(&responses[1]), &responses[1]["id"]), (JsonRpcError::METHOD_NOT_FOUND, &json!(2)));
+        assert_eq!((error_code(&responses[2]), &responses[2]["id"]), (JsonRpcError::INVALID_REQUEST, &json!(3)));
+    }
+
+    #[tokio::test]
+    async fn notifications_are_not_answered() {
+        assert!(dispatch(Arc::new(Calculator), r#"{"jsonrpc":"2.0","method":"add","params":[1,2]}"#).await.is_none());
+        assert!(dispatch(Arc::new(Calculator), r#"{"jsonrpc":"2.0","method":"sub"}"#).await.is_none());
+        assert!(dispatch(Arc::new(Calculator), r#"[{"jsonrpc":"2.0","method":"add","params":[1,2]}]"#).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_are_rejected() {
+        let response = dispatch(Arc::new(Calculator), r#"{"jsonrpc":"2.0","#).await.unwrap();
+        assert_eq!((error_code(&response), &response["id"]), (JsonRpcError::PARSE_ERROR, &Value::Null));
+
+        let response = dispatch(Arc::new(Calculator), "[]").await.unwrap();
+        assert_eq!(error_code(&response), JsonRpcError::INVALID_REQUEST);
+
+        let response = dispatch_one(Arc::new(Calculator), ctx(), json!(7)).await.unwrap();
+        assert_eq!(response.error.unwrap().code, JsonRpcError::INVALID_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn unknown_methods_are_reported() {
+        let request = json!({ "jsonrpc" : "2.0", "method" : "mul", "params" : [1, 2], "id" : 4 });
+        let response = dispatch_one(Arc::new(Calculator), ctx(), request).await.unwrap();
+        assert_eq!(response.id, json!(4));
+        assert_eq!(response.error.unwrap().code, JsonRpcError::METHOD_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_requests_are_rejected_under_their_own_ids() {
+        let text = r#"[
+            {"jsonrpc":"2.0","method":"add","params":[1,2],"id":1},
+            {"jsonrpc":"2.0","method":"add","params":[1,2]},
+            {"jsonrpc":"2.0","method":"add","params":[1,2],"id":"b"}
+        ]"#;
+        let response = dispatch(Arc::new(RejectUnauthenticated), text).await.unwrap();
+        let responses = response.as_array().unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!((error_code(&responses[0]), &responses[0]["id"]), (JsonRpcError::UNAUTHORIZED, &json!(1)));
+        assert_eq!((error_code(&responses[1]), &responses[1]["id"]), (JsonRpcError::UNAUTHORIZED, &json!("b")));
+
+        assert!(dispatch(Arc::new(RejectUnauthenticated), r#"{"jsonrpc":"2.0","method":"add"}"#).await.is_none());
+    }
+}
@@ -0,0 +1,82 @@
+use borsh::{BorshSerialize,BorshDeserialize};
+
+/// Number of stream items the server may send ahead of
+/// the client's acknowledgements unless configured otherwise.
+pub const DEFAULT_STREAM_WINDOW : u32 = 16;
+
+/// Number of upload chunks the client may send ahead of
+/// the server's acknowledgements unless configured otherwise.
+pub const DEFAULT_UPLOAD_WINDOW : u32 = 16;
+
+/// Payload of a [`CtlOp::Upload`](super::message::CtlOp::Upload) request.
+/// Chunks follow as [`CtlOp::UploadChunk`](super::message::CtlOp::UploadChunk)
+/// frames carrying the same request id.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct UploadRequest {
+    pub op : u32,
+    pub data : Vec<u8>,
+}
+
+/// Payload of a [`CtlOp::Stream`](super::message::CtlOp::Stream) request.
+/// The request id in the header identifies the stream for the item
+/// frames, acknowledgements and cancellation.
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct StreamRequest {
+    pub op : u32,
+    /// Initial number of items the server may send before
+    /// waiting for a [`CtlOp::StreamAck`](super::message::CtlOp::StreamAck)
+    pub window : u32,
+    pub data : Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asynchronous::message::*;
+
+    #[test]
+    fn stream_wire_format_is_stable() {
+        assert_eq!(CtlOp::Stream as u32, 0xffff_ff02);
+        assert_eq!(CtlOp::StreamAck as u32, 0xffff_ff03);
+        assert_eq!(RespStatus::StreamItem as u32, 6);
+        assert_eq!(RespStatus::StreamEnd as u32, 7);
+
+        let request = StreamRequest { op : 7, window : 16, data : vec![1, 2] };
+        assert_eq!(
+            request.try_to_vec().unwrap(),
+            vec![7, 0, 0, 0, 16, 0, 0, 0, 2, 0, 0, 0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn stream_ack_frame_round_trip() {
+        let frame = to_vec((ReqHeader { id : 3, op : CtlOp::StreamAck as u32 }, Message::Request(&8u32.try_to_vec().unwrap())));
+        let req = ReqMessage::try_from(&frame[..]).unwrap();
+        assert_eq!(req.id, 3);
+        assert!(matches!(CtlOp::try_from(req.op), Ok(CtlOp::StreamAck)));
+        assert_eq!(u32::try_from_slice(req.data).unwrap(), 8);
+    }
+
+    #[test]
+    fn upload_wire_format_is_stable() {
+        assert_eq!(CtlOp::Upload as u32, 0xffff_ff04);
+        assert_eq!(CtlOp::UploadChunk as u32, 0xffff_ff05);
+        assert_eq!(CtlOp::UploadEnd as u32, 0xffff_ff06);
+        assert_eq!(RespStatus::UploadAck as u32, 8);
+
+        let request = UploadRequest { op : 7, data : vec![1, 2] };
+        assert_eq!(
+            request.try_to_vec().unwrap(),
+            vec![7, 0, 0, 0, 2, 0, 0, 0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn upload_ack_frame_round_trip() {
+        let frame = RespMessage::new(5, RespStatus::UploadAck as u32, &DEFAULT_UPLOAD_WINDOW.try_to_vec().unwrap()).try_to_vec().unwrap();
+        let resp = RespMessage::try_from(&frame[..]).unwrap();
+        assert_eq!(resp.id, 5);
+        assert_eq!(resp.status, RespStatus::UploadAck as u32);
+        assert_eq!(u32::try_from_slice(resp.data).unwrap(), DEFAULT_UPLOAD_WINDOW);
+    }
+}
@@ -1,2 +1,10 @@
 pub mod asynchronous;
-pub mod synchronous;
\ No newline at end of file
+pub mod synchronous;
+
+pub use workflow_rpc_macros::rpc;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use async_trait::async_trait;
+    pub use borsh;
+}
\ No newline at end of file
@@ -0,0 +1,314 @@
+use std::{
+    io::ErrorKind,
+    marker::PhantomData,
+    net::TcpStream,
+    sync::{Mutex, atomic::{AtomicU64, Ordering}},
+    time::{Duration, Instant},
+};
+use borsh::{BorshSerialize,BorshDeserialize};
+use tungstenite::{
+    WebSocket,
+    stream::MaybeTlsStream,
+    Message as WebSocketMessage,
+};
+use workflow_log::log_trace;
+use super::*;
+use super::error::Error;
+use super::result::Result;
+
+/// Blocking RPC client speaking the binary protocol of the
+/// [`asynchronous`](crate::asynchronous) module. Calls are executed
+/// one at a time on the calling thread; notifications received while
+/// waiting for a response are discarded. Only plain `ws://` connections
+/// are supported, as call timeouts are applied to the TCP stream.
+///
+/// The connection is closed when a call fails in a way that leaves it
+/// unusable, including a timeout, so that [`is_open()`](Self::is_open)
+/// reflects whether further calls can be made.
+pub struct RpcClient<Ops>
+where
+    Ops : Into<u32>
+{
+    url : String,
+    socket : Mutex<Option<WebSocket<MaybeTlsStream<TcpStream>>>>,
+    timeout_duration : AtomicU64,
+    credentials : Mutex<Option<RpcCredentials>>,
+    _ops_ : PhantomData<Ops>,
+}
+
+impl<Ops> RpcClient<Ops>
+where
+    Ops : Into<u32>
+{
+    pub fn new(url : &str) -> RpcClient<Ops> {
+        RpcClient {
+            url : url.to_string(),
+            socket : Mutex::new(None),
+            timeout_duration : AtomicU64::new(60_000),
+            credentials : Mutex::new(None),
+            _ops_ : PhantomData,
+        }
+    }
+
+    /// Default timeout applied to calls that do not specify one.
+    pub fn set_timeout(&self, timeout : Duration) {
+        self.timeout_duration.store(timeout.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Configure credentials used to perform the authentication
+    /// handshake when connecting.
+    pub fn set_credentials(&self, credentials : Option<RpcCredentials>) {
+        *self.credentials.lock().unwrap() = credentials;
+    }
+
+    /// Open the connection, replacing any existing one, and perform
+    /// the handshake if credentials have been configured. The client
+    /// is left disconnected if the handshake fails.
+    pub fn connect(&self) -> Result<()> {
+        let (socket, _) = tungstenite::connect(self.url.as_str())?;
+        if !matches!(socket.get_ref(), MaybeTlsStream::Plain(_)) {
+            return Err(Error::TlsNotSupported);
+        }
+        *self.socket.lock().unwrap() = Some(socket);
+        if let Err(err) = self.handshake() {
+            if let Some(mut socket) = self.socket.lock().unwrap().take() {
+                let _ = socket.close(None);
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    pub fn disconnect(&self) -> Result<()> {
+        if let Some(mut socket) = self.socket.lock().unwrap().take() {
+            socket.close(None)?;
+            // drain until the server acknowledges the close frame
+            while socket.read_message().is_ok() { }
+        }
+        Ok(())
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.socket.lock().unwrap().is_some()
+    }
+
+    fn handshake(&self) -> Result<Option<RpcIdentity>> {
+        let credentials = self.credentials.lock().unwrap().clone();
+        let timeout = self.timeout();
+        let identity = match credentials {
+            None => { return Ok(None); },
+            Some(RpcCredentials::Token(token)) => {
+                match self.handshake_step(HandshakeRequest::Token(token), timeout)? {
+                    HandshakeResponse::Accepted(identity) => identity,
+                    _ => { return Err(Error::Handshake); }
+                }
+            },
+            Some(RpcCredentials::ChallengeResponse(respond)) => {
+                let challenge = match self.handshake_step(HandshakeRequest::Challenge, timeout)? {
+                    HandshakeResponse::Challenge(challenge) => challenge,
+                    _ => { return Err(Error::Handshake); }
+                };
+                match self.handshake_step(HandshakeRequest::Response(respond(&challenge[..])), timeout)? {
+                    HandshakeResponse::Accepted(identity) => identity,
+                    _ => { return Err(Error::Handshake); }
+                }
+            }
+        };
+        Ok(Some(identity))
+    }
+
+    fn handshake_step(&self, request : HandshakeRequest, timeout : Duration) -> Result<HandshakeResponse> {
+        let data = request.try_to_vec().map_err(|_| { Error::BorshSerialize })?;
+        let resp = self.call_with_buffer(CtlOp::Handshake as u32, &data, timeout)?;
+        HandshakeResponse::try_from_slice(&resp).map_err(|e|Error::BorshDeserialize(e.to_string()))
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_duration.load(Ordering::SeqCst))
+    }
+
+    /// Post a raw request and block until the matching response
+    /// arrives or `timeout` elapses. The connection is dropped if the
+    /// call fails with an error after which it can not be used.
+    pub fn call_with_buffer(&self, op : u32, data : &[u8], timeout : Duration) -> Result<Vec<u8>> {
+        let mut socket = self.socket.lock().unwrap();
+        let result = match socket.as_mut() {
+            Some(socket) => Self::exchange(socket, op, data, timeout),
+            None => Err(Error::NotConnected),
+        };
+        if matches!(&result, Err(err) if err.is_fatal()) {
+            socket.take();
+        }
+        result
+    }
+
+    fn exchange(socket : &mut WebSocket<MaybeTlsStream<TcpStream>>, op : u32, data : &[u8], timeout : Duration) -> Result<Vec<u8>> {
+        let id = u64::from_le_bytes(rand::random::<[u8; 8]>());
+        socket.write_message(WebSocketMessage::Binary(to_vec((ReqHeader{op,id},Message::Request(data)))))?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Error::Timeout);
+            }
+            match socket.get_mut() {
+                MaybeTlsStream::Plain(stream) => stream.set_read_timeout(Some(remaining))?,
+                _ => { return Err(Error::TlsNotSupported); }
+            }
+
+            let data = match socket.read_message() {
+                Ok(WebSocketMessage::Binary(data)) => data,
+                Ok(WebSocketMessage::Close(_)) => {
+                    return Err(Error::NotConnected);
+                },
+                Ok(_) => { continue; },
+                Err(tungstenite::Error::Io(err)) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Err(Error::Timeout);
+                },
+                Err(err) => {
+                    return Err(err.into());
+                }
+            };
+
+            let msg = RespMessage::try_from(&data[..]).map_err(|_| Error::HeaderSize)?;
+            if msg.status == RespStatus::Notification as u32 || msg.id != id {
+                log_trace!("rpc ignoring message with id {} and status {}", msg.id, msg.status);
+                continue;
+            }
+
+            return match RespStatus::try_from(msg.status) {
+                Ok(RespStatus::Success) => Ok(msg.data.to_vec()),
+                Ok(RespStatus::Error) => {
+                    let err = RpcResponseError::try_from_slice(msg.data).map_err(|_| Error::ErrorDeserializingResponseData)?;
+                    Err(Error::RpcCall(err))
+                },
+                Ok(RespStatus::Unauthorized) => {
+                    let err = RpcResponseError::try_from_slice(msg.data).map_err(|_| Error::ErrorDeserializingResponseData)?;
+                    Err(Error::Unauthorized(err))
+                },
+                Ok(RespStatus::MalformedHeader) => Err(Error::MalformedHeader),
+                Ok(RespStatus::UnknownOp) => Err(Error::UnknownOp),
+                _ => Err(Error::StatusCode(msg.status)),
+            };
+        }
+    }
+
+    /// Issue a call using the default timeout.
+    pub fn call<Req,Resp>(&self, op : Ops, req : Req) -> Result<Resp>
+    where
+        Req : BorshSerialize,
+        Resp : BorshDeserialize,
+    {
+        self.call_with_timeout(op, req, self.timeout())
+    }
+
+    /// Issue a call that fails with [`Error::Timeout`] if no response
+    /// is received within `timeout`.
+    pub fn call_with_timeout<Req,Resp>(&self, op : Ops, req : Req, timeout : Duration) -> Result<Resp>
+    where
+        Req : BorshSerialize,
+        Resp : BorshDeserialize,
+    {
+        let data = req.try_to_vec().map_err(|_| { Error::BorshSerialize })?;
+        let resp = self.call_with_buffer(op.into(), &data, timeout)?;
+        Resp::try_from_slice(&resp).map_err(|e|Error::BorshDeserialize(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Serve a single connection, answering each request with the
+    /// frames produced by `respond`. Returns the URL to connect to.
+    fn serve<F>(respond : F) -> String
+    where
+        F : Fn(ReqMessage<'_>) -> Vec<Vec<u8>> + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            while let Ok(msg) = socket.read_message() {
+                if let WebSocketMessage::Binary(data) = msg {
+                    let req = ReqMessage::try_from(&data[..]).unwrap();
+                    for frame in respond(req) {
+                        if socket.write_message(WebSocketMessage::Binary(frame)).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        url
+    }
+
+    fn response(id : u64, status : RespStatus, data : &[u8]) -> Vec<u8> {
+        RespMessage::new(id, status as u32, data).try_to_vec().unwrap()
+    }
+
+    /// Answers op 0 with the request incremented by one, op 1 with an
+    /// error and leaves other ops unanswered.
+    fn server() -> String {
+        serve(|req| match req.op {
+            0 => {
+                let value = u32::try_from_slice(req.data).unwrap();
+                vec![
+                    notification_to_vec(7, &[1]).unwrap(),
+                    response(req.id.wrapping_add(1), RespStatus::Success, &[]),
+                    response(req.id, RespStatus::Success, &(value + 1).try_to_vec().unwrap()),
+                ]
+            },
+            1 => vec![response(req.id, RespStatus::Error, &RpcResponseError::NotFound.try_to_vec().unwrap())],
+            2 => vec![response(req.id, RespStatus::UnknownOp, &[])],
+            _ => vec![],
+        })
+    }
+
+    #[test]
+    fn calls_receive_their_own_response() {
+        let client = RpcClient::<u32>::new(&server());
+        assert!(matches!(client.call::<u32, u32>(0, 1), Err(Error::NotConnected)));
+
+        client.connect().unwrap();
+        assert_eq!(client.call::<u32, u32>(0, 1).unwrap(), 2);
+        assert_eq!(client.call::<u32, u32>(0, 5).unwrap(), 6);
+        client.disconnect().unwrap();
+        assert!(!client.is_open());
+    }
+
+    #[test]
+    fn error_responses_keep_the_connection() {
+        let client = RpcClient::<u32>::new(&server());
+        client.connect().unwrap();
+        assert!(matches!(client.call::<u32, u32>(1, 1), Err(Error::RpcCall(err)) if err.kind() == "NotFound"));
+        assert!(matches!(client.call::<u32, u32>(2, 1), Err(Error::UnknownOp)));
+        assert!(client.is_open());
+        assert_eq!(client.call::<u32, u32>(0, 1).unwrap(), 2);
+    }
+
+    #[test]
+    fn timeouts_close_the_connection() {
+        let client = RpcClient::<u32>::new(&server());
+        client.connect().unwrap();
+        let result = client.call_with_timeout::<u32, u32>(3, 1, Duration::from_millis(50));
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert!(!client.is_open());
+        assert!(matches!(client.call::<u32, u32>(0, 1), Err(Error::NotConnected)));
+    }
+
+    #[test]
+    fn rejected_handshakes_leave_the_client_disconnected() {
+        let url = serve(|req| {
+            assert_eq!(req.op, CtlOp::Handshake as u32);
+            vec![response(req.id, RespStatus::Unauthorized, &RpcResponseError::Forbidden.try_to_vec().unwrap())]
+        });
+        let client = RpcClient::<u32>::new(&url);
+        client.set_credentials(Some(RpcCredentials::Token("secret".to_string())));
+        assert!(matches!(client.connect(), Err(Error::Unauthorized(err)) if err.kind() == "Forbidden"));
+        assert!(!client.is_open());
+    }
+}
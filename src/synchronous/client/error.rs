@@ -0,0 +1,69 @@
+use thiserror::Error;
+use crate::asynchronous::error::RpcResponseError;
+
+#[derive(Error, Debug)]
+pub enum Error {
+
+    /// Underlying WebSocket error
+    #[error("WebSocket error: {0}")]
+    WebSocketError(#[from] tungstenite::Error),
+    /// Socket configuration error
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+    /// Client is not connected
+    #[error("RPC: not connected")]
+    NotConnected,
+    /// The connection does not run over a plain TCP stream; call
+    /// timeouts can not be applied to TLS streams
+    #[error("RPC: TLS connections are not supported")]
+    TlsNotSupported,
+    /// RPC call timeout
+    #[error("RPC request timeout")]
+    Timeout,
+    /// Received message is smaller than the minimum header size
+    #[error("Invalid header size")]
+    HeaderSize,
+    /// Unable to deserialize response data
+    #[error("RPC: error deserializing response data")]
+    ErrorDeserializingResponseData,
+    /// Response produced an unknown status code
+    #[error("RPC: status code {0}")]
+    StatusCode(u32),
+    /// RPC call executed successfully but produced an error response
+    #[error("RPC: response error {0:?}")]
+    RpcCall(RpcResponseError),
+    /// Server rejected the request or the handshake credentials
+    #[error("RPC: unauthorized {0:?}")]
+    Unauthorized(RpcResponseError),
+    /// Server responded to the handshake with an unexpected message
+    #[error("RPC: unexpected handshake response")]
+    Handshake,
+    /// Server was unable to decode the request header
+    #[error("RPC: malformed request header")]
+    MalformedHeader,
+    /// Server does not recognize the request op
+    #[error("RPC: unknown op")]
+    UnknownOp,
+    /// Unable to serialize borsh data
+    #[error("RPC: borsh serialization error")]
+    BorshSerialize,
+    /// Unable to deserialize borsh data
+    #[error("RPC borsh deserialization error: {0}")]
+    BorshDeserialize(String),
+}
+
+impl Error {
+    /// Whether the connection can not be used after this error. The
+    /// request or response stream may be left incomplete, or a late
+    /// response may arrive for a call that has already failed.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self,
+            Error::WebSocketError(_)
+            | Error::IoError(_)
+            | Error::NotConnected
+            | Error::TlsNotSupported
+            | Error::Timeout
+            | Error::HeaderSize
+        )
+    }
+}
@@ -0,0 +1,10 @@
+pub use crate::asynchronous::error::*;
+pub use crate::asynchronous::message::*;
+pub use crate::asynchronous::auth::*;
+
+mod client;
+pub use self::client::*;
+
+pub mod error;
+
+pub mod result;
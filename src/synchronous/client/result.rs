@@ -0,0 +1,2 @@
+use super::error::Error;
+pub type Result<T> = std::result::Result<T, Error>;
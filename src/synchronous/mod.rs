@@ -0,0 +1,5 @@
+#[cfg(not(any(target_arch = "wasm32", target_os = "solana")))]
+pub mod client;
+
+#[cfg(not(any(target_arch = "wasm32", target_os = "solana")))]
+pub mod server;
@@ -0,0 +1,20 @@
+use std::sync::Arc;
+use crate::asynchronous::auth::RpcIdentity;
+use crate::asynchronous::error::RpcResponseError;
+use super::context::RpcContext;
+
+pub use crate::asynchronous::auth::AuthCredentials;
+
+/// Blocking counterpart of the asynchronous
+/// [`RpcAuthenticator`](crate::asynchronous::server::RpcAuthenticator).
+/// Called on the connection thread, so requests following the
+/// handshake are processed only once it has completed.
+pub trait RpcAuthenticator : Send + Sync + 'static {
+    /// Produce a challenge for challenge-response authentication.
+    fn challenge(&self, _ctx : &Arc<RpcContext>) -> Result<Vec<u8>, RpcResponseError> {
+        Err(RpcResponseError::Text("challenge-response authentication is not supported".to_string()))
+    }
+
+    /// Validate credentials and produce the identity attached to the connection.
+    fn authenticate(&self, ctx : &Arc<RpcContext>, credentials : AuthCredentials) -> Result<RpcIdentity, RpcResponseError>;
+}
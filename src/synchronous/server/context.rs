@@ -0,0 +1,102 @@
+use std::any::Any;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, mpsc::{channel, Sender}};
+use borsh::BorshSerialize;
+use crate::asynchronous::auth::RpcIdentity;
+use crate::asynchronous::message::*;
+use super::error::Error;
+use super::result::Result;
+
+/// Per-connection context supplied to every blocking
+/// [`RpcHandler`](super::RpcHandler) call made on behalf
+/// of the connection.
+pub struct RpcContext {
+    pub peer : SocketAddr,
+    sink : Mutex<Sender<tungstenite::Message>>,
+    session : Mutex<Option<Arc<dyn Any + Send + Sync>>>,
+    identity : Mutex<Option<RpcIdentity>>,
+    challenge : Mutex<Option<Vec<u8>>>,
+}
+
+impl RpcContext {
+    pub(crate) fn new(peer : SocketAddr, sink : Sender<tungstenite::Message>) -> Self {
+        RpcContext {
+            peer,
+            sink : Mutex::new(sink),
+            session : Mutex::new(None),
+            identity : Mutex::new(None),
+            challenge : Mutex::new(None),
+        }
+    }
+
+    /// Attach a user-defined session payload to this connection,
+    /// replacing any previously stored payload.
+    pub fn set_session<T>(&self, session : T)
+    where
+        T : Any + Send + Sync
+    {
+        *self.session.lock().unwrap() = Some(Arc::new(session));
+    }
+
+    /// Obtain the session payload if one is present and is of type `T`.
+    pub fn session<T>(&self) -> Option<Arc<T>>
+    where
+        T : Any + Send + Sync
+    {
+        let session = self.session.lock().unwrap().clone()?;
+        session.downcast::<T>().ok()
+    }
+
+    /// Identity attached by a successful authentication handshake.
+    pub fn identity(&self) -> Option<RpcIdentity> {
+        self.identity.lock().unwrap().clone()
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.identity.lock().unwrap().is_some()
+    }
+
+    pub(crate) fn set_identity(&self, identity : RpcIdentity) {
+        *self.identity.lock().unwrap() = Some(identity);
+    }
+
+    pub(crate) fn set_challenge(&self, challenge : Vec<u8>) {
+        *self.challenge.lock().unwrap() = Some(challenge);
+    }
+
+    pub(crate) fn take_challenge(&self) -> Option<Vec<u8>> {
+        self.challenge.lock().unwrap().take()
+    }
+
+    /// Close the connection. Frames posted before the call are
+    /// delivered first.
+    pub fn close(&self) -> Result<()> {
+        self.send(tungstenite::Message::Close(None))
+    }
+
+    /// Stop delivering frames to the peer once the connection has
+    /// been closed by the peer. Frames posted afterwards are rejected.
+    pub(crate) fn detach(&self) {
+        *self.sink.lock().unwrap() = channel().0;
+    }
+
+    pub(crate) fn send(&self, msg : tungstenite::Message) -> Result<()> {
+        self.sink.lock().unwrap().send(msg).map_err(|e| Error::SinkSend(e.to_string()))
+    }
+
+    /// Post a raw notification to the peer.
+    pub fn notify_with_buffer(&self, op : u32, data : &[u8]) -> Result<()> {
+        let msg = notification_to_vec(op, data).map_err(|_| Error::BorshSerialize)?;
+        self.send(msg.into())
+    }
+
+    /// Serialize `msg` and post it to the peer as a notification for `op`.
+    pub fn notify<Op, Msg>(&self, op : Op, msg : &Msg) -> Result<()>
+    where
+        Op : Into<u32>,
+        Msg : BorshSerialize,
+    {
+        let data = msg.try_to_vec().map_err(|_| Error::BorshSerialize)?;
+        self.notify_with_buffer(op.into(), &data)
+    }
+}
@@ -0,0 +1,19 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+
+    #[error("WebSocket error: {0}")]
+    WebSocketError(#[from] tungstenite::Error),
+
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    /// Unable to post a message to the connection
+    #[error("Sink error: {0}")]
+    SinkSend(String),
+
+    /// Unable to serialize borsh data
+    #[error("RPC: borsh serialization error")]
+    BorshSerialize,
+}
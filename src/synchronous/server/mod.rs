@@ -0,0 +1,19 @@
+pub use crate::asynchronous::error::*;
+pub use crate::asynchronous::message::*;
+pub use crate::asynchronous::result::*;
+
+mod server;
+pub use self::server::*;
+
+mod context;
+pub use self::context::*;
+
+mod auth;
+pub use self::auth::*;
+
+mod pool;
+mod socket;
+
+pub mod error;
+
+pub mod result;
@@ -0,0 +1,77 @@
+use std::sync::{Arc, Mutex, mpsc::{channel, Sender, Receiver}};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed-size pool of worker threads executing blocking handler calls.
+pub struct ThreadPool {
+    sender : Mutex<Sender<Job>>,
+}
+
+impl ThreadPool {
+    pub fn new(threads : usize) -> ThreadPool {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::spawn(move || Self::worker(receiver));
+        }
+
+        ThreadPool {
+            sender : Mutex::new(sender),
+        }
+    }
+
+    fn worker(receiver : Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            let job = receiver.lock().unwrap().recv();
+            match job {
+                Ok(job) => job(),
+                Err(_) => break,
+            }
+        }
+    }
+
+    pub fn execute<F>(&self, job : F)
+    where
+        F : FnOnce() + Send + 'static
+    {
+        // workers only exit once the pool is dropped
+        self.sender.lock().unwrap().send(Box::new(job)).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn jobs_run_on_every_worker() {
+        let pool = ThreadPool::new(2);
+        let (release, released) = channel();
+        let (done, finished) = channel();
+
+        // the first job only completes if the second one runs alongside it
+        let first = done.clone();
+        pool.execute(move || {
+            first.send(released.recv_timeout(Duration::from_secs(5)).is_ok()).unwrap();
+        });
+        pool.execute(move || {
+            release.send(()).unwrap();
+            done.send(true).unwrap();
+        });
+
+        for _ in 0..2 {
+            assert!(finished.recv_timeout(Duration::from_secs(5)).unwrap());
+        }
+    }
+
+    #[test]
+    fn pools_have_at_least_one_worker() {
+        let pool = ThreadPool::new(0);
+        let (done, finished) = channel();
+        pool.execute(move || done.send(()).unwrap());
+        assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
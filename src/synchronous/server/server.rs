@@ -0,0 +1,535 @@
+use std::mem::size_of;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, mpsc::channel};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use ahash::AHashMap;
+use borsh::BorshSerialize;
+use tungstenite::{Message as WebSocketMessage, WebSocket};
+use tungstenite::protocol::{CloseFrame, Role, frame::coding::CloseCode};
+use workflow_log::*;
+use crate::asynchronous::auth::*;
+use crate::asynchronous::error::RpcResponseError;
+use crate::asynchronous::message::*;
+use crate::asynchronous::result::RpcResult;
+use super::auth::*;
+use super::context::RpcContext;
+use super::pool::ThreadPool;
+use super::result::Result;
+use super::socket::SocketHalf;
+
+/// Blocking counterpart of the asynchronous
+/// [`RpcHandler`](crate::asynchronous::server::RpcHandler).
+/// Requests are executed on the server thread pool.
+pub trait RpcHandler<Ops> : Send + Sync + 'static
+where
+    Ops : Send + 'static
+{
+    /// Called when a peer connects. Returning an error rejects the connection.
+    fn connect(&self, _ctx : &Arc<RpcContext>) -> std::result::Result<(), RpcResponseError> {
+        Ok(())
+    }
+
+    /// Called once the connection has been closed.
+    fn disconnect(&self, _ctx : &Arc<RpcContext>) { }
+
+    fn handle_request(&self, ctx : &Arc<RpcContext>, op : Ops, data : &[u8]) -> RpcResult;
+}
+
+/// Options controlling the behavior of the blocking [`RpcServer`].
+#[derive(Clone)]
+pub struct RpcServerOptions {
+    /// Number of worker threads executing requests
+    pub threads : usize,
+    /// Credential validation performed during the handshake.
+    /// When set, connections must complete the handshake before
+    /// any requests are processed. When not set, handshakes are
+    /// accepted with an anonymous identity.
+    pub authenticator : Option<Arc<dyn RpcAuthenticator>>,
+}
+
+impl Default for RpcServerOptions {
+    fn default() -> Self {
+        RpcServerOptions {
+            threads : 4,
+            authenticator : None,
+        }
+    }
+}
+
+/// Blocking RPC server speaking the binary protocol of the
+/// [`asynchronous`](crate::asynchronous) module, allowing
+/// asynchronous and wasm clients to connect unchanged.
+pub struct RpcServer<Ops>
+where
+    Ops : Send + TryFrom<u32> + 'static
+{
+    rpc_handler : Arc<dyn RpcHandler<Ops>>,
+    authenticator : Option<Arc<dyn RpcAuthenticator>>,
+    pool : ThreadPool,
+    stopped : AtomicBool,
+    local_addr : Mutex<Option<SocketAddr>>,
+    connections : Mutex<AHashMap<u64, TcpStream>>,
+    next_connection_id : AtomicU64,
+}
+
+impl<Ops> RpcServer<Ops>
+where
+    Ops : Send + TryFrom<u32> + 'static
+{
+    /// Create a server executing requests on `threads` worker threads.
+    pub fn new(rpc_handler : Arc<dyn RpcHandler<Ops>>, threads : usize) -> Arc<RpcServer<Ops>> {
+        Self::new_with_options(rpc_handler, RpcServerOptions { threads, ..Default::default() })
+    }
+
+    pub fn new_with_options(rpc_handler : Arc<dyn RpcHandler<Ops>>, options : RpcServerOptions) -> Arc<RpcServer<Ops>> {
+        Arc::new(RpcServer {
+            rpc_handler,
+            authenticator : options.authenticator,
+            pool : ThreadPool::new(options.threads),
+            stopped : AtomicBool::new(false),
+            local_addr : Mutex::new(None),
+            connections : Mutex::new(AHashMap::new()),
+            next_connection_id : AtomicU64::new(0),
+        })
+    }
+
+    /// Address the server is bound to, once `listen()` has been called.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self.local_addr.lock().unwrap()
+    }
+
+    /// Accept connections on `addr`, blocking the calling thread
+    /// until [`stop()`](Self::stop) is called. Each connection is
+    /// served by a dedicated reader and writer thread.
+    pub fn listen(self : &Arc<Self>, addr : &str) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        *self.local_addr.lock().unwrap() = Some(listener.local_addr()?);
+        for stream in listener.incoming() {
+            if self.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+
+            match stream {
+                Ok(stream) => {
+                    let this = self.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = this.connection(stream) {
+                            log_trace!("RPC connection error: {}", err);
+                        }
+                    });
+                },
+                Err(err) => {
+                    log_error!("RPC accept error: {}", err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop accepting connections and close all open connections,
+    /// causing `listen()` to return.
+    pub fn stop(&self) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // wake the accept loop so that it observes the stop flag
+        if let Some(mut addr) = self.local_addr() {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(if addr.is_ipv4() { Ipv4Addr::LOCALHOST.into() } else { Ipv6Addr::LOCALHOST.into() });
+            }
+            TcpStream::connect(addr).ok();
+        }
+
+        for (_, stream) in self.connections.lock().unwrap().drain() {
+            stream.shutdown(Shutdown::Both).ok();
+        }
+    }
+
+    fn connection(self : &Arc<Self>, stream : TcpStream) -> Result<()> {
+        if self.stopped.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let peer = stream.peer_addr()?;
+        let writer = Arc::new(Mutex::new(stream.try_clone()?));
+        let control = stream.try_clone()?;
+        let closer = stream.try_clone()?;
+        let mut write_half = SocketHalf::new(stream.try_clone()?, writer.clone());
+        write_half.set_buffered();
+        let mut socket = tungstenite::accept(SocketHalf::new(stream, writer)).map_err(|err| match err {
+            tungstenite::HandshakeError::Failure(err) => err,
+            tungstenite::HandshakeError::Interrupted(_) => tungstenite::Error::ConnectionClosed,
+        })?;
+        socket.get_mut().set_buffered();
+
+        let (sender, receiver) = channel::<WebSocketMessage>();
+        let ctx = Arc::new(RpcContext::new(peer, sender));
+        if let Err(err) = self.rpc_handler.connect(&ctx) {
+            log_trace!("RPC connection from {} rejected: {:?}", peer, err);
+            socket.close(None)?;
+            return Ok(());
+        }
+
+        let connection_id = self.next_connection_id.fetch_add(1, Ordering::SeqCst);
+        self.connections.lock().unwrap().insert(connection_id, control.try_clone()?);
+        // `stop()` may have drained the registry before the insert
+        if self.stopped.load(Ordering::SeqCst) {
+            control.shutdown(Shutdown::Both).ok();
+        }
+
+        // responses are written by a dedicated thread as soon as
+        // workers produce them, while this thread blocks on reads
+        let writer = thread::spawn(move || {
+            let mut socket = WebSocket::from_raw_socket(write_half, Role::Server, None);
+            while let Ok(msg) = receiver.recv() {
+                let close = matches!(msg, WebSocketMessage::Close(_));
+                if socket.write_message(msg).is_err() || close {
+                    break;
+                }
+            }
+            // unblock the reader if the connection is closed from this side
+            control.shutdown(Shutdown::Both).ok();
+        });
+
+        let closed_by_peer = loop {
+            match socket.read_message() {
+                Ok(WebSocketMessage::Binary(data)) => {
+                    self.dispatch(&ctx, data);
+                },
+                Ok(WebSocketMessage::Close(_)) => {
+                    // the reply to the peer's close frame is queued
+                    // by the reading socket and written out here
+                    socket.write_pending().ok();
+                    break true;
+                },
+                Ok(_) => { },
+                Err(err) => {
+                    log_trace!("RPC connection from {} terminated: {}", peer, err);
+                    break false;
+                }
+            }
+        };
+
+        if closed_by_peer {
+            // the close handshake is complete; stop the writer
+            // without sending a close frame of its own
+            closer.shutdown(Shutdown::Both).ok();
+            ctx.detach();
+        } else {
+            // the writer exits once the close frame is written or
+            // immediately if the socket is gone
+            ctx.close().ok();
+        }
+        writer.join().ok();
+        self.connections.lock().unwrap().remove(&connection_id);
+        self.rpc_handler.disconnect(&ctx);
+        Ok(())
+    }
+
+    fn respond(ctx : &RpcContext, id : u64, status : RespStatus, data : &[u8]) {
+        if let Ok(msg) = RespMessage::new(id, status as u32, data).try_to_vec() {
+            if let Err(e) = ctx.send(msg.into()) {
+                log_trace!("Sink error: {:?}", e);
+            }
+        }
+    }
+
+    fn respond_with_error(ctx : &RpcContext, id : u64, status : RespStatus, err : RpcResponseError) {
+        if let Ok(data) = err.try_to_vec() {
+            Self::respond(ctx, id, status, &data);
+        }
+    }
+
+    fn dispatch(self : &Arc<Self>, ctx : &Arc<RpcContext>, data : Vec<u8>) {
+        let req : ReqMessage = match (&data).try_into() {
+            Ok(req) => req,
+            Err(_) => {
+                if data.len() >= size_of::<u64>() {
+                    let id = u64::from_ne_bytes(data[..size_of::<u64>()].try_into().unwrap());
+                    Self::respond(ctx, id, RespStatus::MalformedHeader, &[]);
+                } else {
+                    let frame = CloseFrame {
+                        code : CloseCode::Protocol,
+                        reason : "malformed RPC frame".into(),
+                    };
+                    ctx.send(WebSocketMessage::Close(Some(frame))).ok();
+                }
+                return;
+            }
+        };
+
+        let id = req.id;
+        if req.op == CtlOp::Handshake as u32 {
+            self.handle_handshake(ctx, id, &data[size_of::<ReqHeader>()..]);
+            return;
+        }
+
+        if let Err(err) = check_handshake(self.authenticator.is_some(), ctx.is_authenticated()) {
+            Self::respond_with_error(ctx, id, RespStatus::Unauthorized, err);
+            return;
+        }
+
+        let op = match Ops::try_from(req.op) {
+            Ok(op) => op,
+            Err(_) => {
+                log_trace!("RPC unknown request opcode {} from {}", req.op, ctx.peer);
+                Self::respond(ctx, id, RespStatus::UnknownOp, &[]);
+                return;
+            }
+        };
+
+        let rpc_handler = self.rpc_handler.clone();
+        let ctx = ctx.clone();
+        self.pool.execute(move || {
+            let payload = &data[size_of::<ReqHeader>()..];
+            match rpc_handler.handle_request(&ctx, op, payload) {
+                Ok(data) => {
+                    Self::respond(&ctx, id, RespStatus::Success, &data);
+                },
+                Err(RpcResponseError::NotFound) => {
+                    Self::respond(&ctx, id, RespStatus::UnknownOp, &[]);
+                },
+                Err(err) => {
+                    Self::respond_with_error(&ctx, id, RespStatus::Error, err);
+                }
+            }
+        });
+    }
+
+    fn handle_handshake(&self, ctx : &Arc<RpcContext>, id : u64, data : &[u8]) {
+        let result = match HandshakeStep::decode(data, self.authenticator.is_some(), || ctx.take_challenge()) {
+            Ok(step) => self.authenticate(ctx, step),
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(response) => {
+                if let Ok(data) = response.try_to_vec() {
+                    Self::respond(ctx, id, RespStatus::Success, &data);
+                }
+            },
+            Err(err) => {
+                log_trace!("RPC handshake failure for {}: {:?}", ctx.peer, err);
+                Self::respond_with_error(ctx, id, RespStatus::Unauthorized, err);
+            }
+        }
+    }
+
+    /// Perform a handshake step with the authenticator, attaching
+    /// the resulting identity to the connection.
+    fn authenticate(&self, ctx : &Arc<RpcContext>, step : HandshakeStep) -> std::result::Result<HandshakeResponse, RpcResponseError> {
+        let result = match (step, &self.authenticator) {
+            (HandshakeStep::Challenge, Some(authenticator)) => {
+                authenticator.challenge(ctx)
+                    .map(|challenge| {
+                        ctx.set_challenge(challenge.clone());
+                        HandshakeResponse::Challenge(challenge)
+                    })
+            },
+            (HandshakeStep::Authenticate(credentials), Some(authenticator)) => {
+                authenticator.authenticate(ctx, credentials)
+                    .map(HandshakeResponse::Accepted)
+            },
+            (HandshakeStep::Anonymous, _) | (_, None) => {
+                Ok(HandshakeResponse::Accepted(RpcIdentity::default()))
+            }
+        };
+
+        if let Ok(HandshakeResponse::Accepted(identity)) = &result {
+            ctx.set_identity(identity.clone());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use borsh::BorshDeserialize;
+    use std::time::Duration;
+    use tungstenite::stream::MaybeTlsStream;
+
+    #[derive(Debug)]
+    enum TestOps {
+        Echo = 0,
+        Fail = 1,
+    }
+
+    impl TryFrom<u32> for TestOps {
+        type Error = ();
+        fn try_from(op : u32) -> std::result::Result<Self, Self::Error> {
+            match op {
+                0 => Ok(TestOps::Echo),
+                1 => Ok(TestOps::Fail),
+                _ => Err(()),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Handler {
+        disconnected : AtomicBool,
+    }
+
+    impl RpcHandler<TestOps> for Handler {
+        fn disconnect(&self, _ctx : &Arc<RpcContext>) {
+            self.disconnected.store(true, Ordering::SeqCst);
+        }
+
+        fn handle_request(&self, _ctx : &Arc<RpcContext>, op : TestOps, data : &[u8]) -> RpcResult {
+            match op {
+                TestOps::Echo => Ok(data.to_vec()),
+                TestOps::Fail => Err(RpcResponseError::Text("failed".to_string())),
+            }
+        }
+    }
+
+    struct Tokens;
+
+    impl RpcAuthenticator for Tokens {
+        fn authenticate(&self, _ctx : &Arc<RpcContext>, credentials : AuthCredentials) -> std::result::Result<RpcIdentity, RpcResponseError> {
+            match credentials {
+                AuthCredentials::Token(token) if token == "secret" => Ok(RpcIdentity::new("alice", &[])),
+                _ => Err(RpcResponseError::Forbidden),
+            }
+        }
+    }
+
+    /// Start a server on an ephemeral port, returning it with its
+    /// handler and the address it listens on.
+    fn start(options : RpcServerOptions) -> (Arc<RpcServer<TestOps>>, Arc<Handler>, SocketAddr) {
+        let handler = Arc::new(Handler::default());
+        let server = RpcServer::new_with_options(handler.clone(), options);
+        let listener = server.clone();
+        thread::spawn(move || listener.listen("127.0.0.1:0").unwrap());
+        loop {
+            if let Some(addr) = server.local_addr() {
+                return (server, handler, addr);
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Client framing requests the way the asynchronous client does.
+    struct Peer {
+        socket : WebSocket<MaybeTlsStream<TcpStream>>,
+    }
+
+    impl Peer {
+        fn connect(addr : SocketAddr) -> Peer {
+            let (mut socket, _) = tungstenite::connect(format!("ws://{}", addr)).unwrap();
+            match socket.get_mut() {
+                MaybeTlsStream::Plain(stream) => stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap(),
+                _ => unreachable!(),
+            }
+            Peer { socket }
+        }
+
+        fn send(&mut self, data : Vec<u8>) {
+            self.socket.write_message(WebSocketMessage::Binary(data)).unwrap();
+        }
+
+        fn recv(&mut self) -> (u64, u32, Vec<u8>) {
+            let data = self.socket.read_message().unwrap().into_data();
+            let msg = RespMessage::try_from(&data[..]).unwrap();
+            (msg.id, msg.status, msg.data.to_vec())
+        }
+
+        fn call(&mut self, id : u64, op : u32, data : &[u8]) -> (u32, Vec<u8>) {
+            self.send(to_vec((ReqHeader { id, op }, Message::Request(data))));
+            let (resp_id, status, data) = self.recv();
+            assert_eq!(resp_id, id);
+            (status, data)
+        }
+    }
+
+    fn error(data : &[u8]) -> RpcResponseError {
+        RpcResponseError::try_from_slice(data).unwrap()
+    }
+
+    #[test]
+    fn requests_are_answered_by_the_handler() {
+        let (server, _, addr) = start(RpcServerOptions { threads : 2, ..Default::default() });
+        let mut peer = Peer::connect(addr);
+
+        assert_eq!(peer.call(1, TestOps::Echo as u32, &[1, 2]), (RespStatus::Success as u32, vec![1, 2]));
+        let (status, data) = peer.call(2, TestOps::Fail as u32, &[]);
+        assert_eq!(status, RespStatus::Error as u32);
+        assert_eq!(error(&data).kind(), "Text");
+        assert_eq!(peer.call(3, 9, &[]), (RespStatus::UnknownOp as u32, vec![]));
+        server.stop();
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        let (server, _, addr) = start(RpcServerOptions::default());
+        let mut peer = Peer::connect(addr);
+
+        // the id is recovered from a truncated header
+        let mut frame = 5u64.to_ne_bytes().to_vec();
+        frame.push(0);
+        peer.send(frame);
+        assert_eq!(peer.recv(), (5, RespStatus::MalformedHeader as u32, vec![]));
+
+        peer.send(vec![0; 4]);
+        match peer.socket.read_message().unwrap() {
+            WebSocketMessage::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Protocol),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        server.stop();
+    }
+
+    #[test]
+    fn requests_require_the_handshake_with_an_authenticator() {
+        let (server, _, addr) = start(RpcServerOptions { authenticator : Some(Arc::new(Tokens)), ..Default::default() });
+        let mut peer = Peer::connect(addr);
+
+        let (status, data) = peer.call(1, TestOps::Echo as u32, &[1]);
+        assert_eq!(status, RespStatus::Unauthorized as u32);
+        assert_eq!(error(&data).kind(), "HandshakeRequired");
+
+        let request = HandshakeRequest::Token("wrong".to_string()).try_to_vec().unwrap();
+        let (status, data) = peer.call(2, CtlOp::Handshake as u32, &request);
+        assert_eq!(status, RespStatus::Unauthorized as u32);
+        assert_eq!(error(&data).kind(), "Forbidden");
+
+        let request = HandshakeRequest::Token("secret".to_string()).try_to_vec().unwrap();
+        let (status, data) = peer.call(3, CtlOp::Handshake as u32, &request);
+        assert_eq!(status, RespStatus::Success as u32);
+        assert!(matches!(HandshakeResponse::try_from_slice(&data).unwrap(), HandshakeResponse::Accepted(identity) if identity.subject == "alice"));
+
+        assert_eq!(peer.call(4, TestOps::Echo as u32, &[1]), (RespStatus::Success as u32, vec![1]));
+        server.stop();
+    }
+
+    #[test]
+    fn peer_close_is_answered_with_a_single_close_frame() {
+        let (server, handler, addr) = start(RpcServerOptions::default());
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut socket = match tungstenite::client(format!("ws://{}", addr), stream) {
+            Ok((socket, _)) => socket,
+            Err(_) => panic!("WebSocket handshake failed"),
+        };
+
+        // masked close frame without a payload, followed by
+        // everything the server sends until it closes the socket
+        let stream = socket.get_mut();
+        stream.write_all(&[0x88, 0x80, 0, 0, 0, 0]).unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        assert_eq!(received, vec![0x88, 0x00]);
+
+        for _ in 0..500 {
+            if handler.disconnected.load(Ordering::SeqCst) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(handler.disconnected.load(Ordering::SeqCst));
+        server.stop();
+    }
+}
@@ -0,0 +1,60 @@
+use std::io::{Read, Result, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+/// One half of a connection socket shared by the reading and the
+/// writing WebSocket of a connection. Reads go straight to the socket.
+/// Once `buffered` is set, writes are collected until flushed and then
+/// written out under a lock shared by both halves, so that frames
+/// produced by either side (responses, pongs, close replies) are never
+/// interleaved on the wire.
+pub(crate) struct SocketHalf {
+    stream : TcpStream,
+    writer : Arc<Mutex<TcpStream>>,
+    buffer : Vec<u8>,
+    buffered : bool,
+}
+
+impl SocketHalf {
+    pub fn new(stream : TcpStream, writer : Arc<Mutex<TcpStream>>) -> Self {
+        SocketHalf {
+            stream,
+            writer,
+            buffer : Vec::new(),
+            buffered : false,
+        }
+    }
+
+    /// Start collecting writes until flushed. The WebSocket handshake
+    /// does not flush its response, so it must be written through.
+    pub fn set_buffered(&mut self) {
+        self.buffered = true;
+    }
+}
+
+impl Read for SocketHalf {
+    fn read(&mut self, buf : &mut [u8]) -> Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for SocketHalf {
+    fn write(&mut self, buf : &[u8]) -> Result<usize> {
+        if self.buffered {
+            self.buffer.extend_from_slice(buf);
+            Ok(buf.len())
+        } else {
+            self.writer.lock().unwrap().write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if !self.buffer.is_empty() {
+            let result = writer.write_all(&self.buffer);
+            self.buffer.clear();
+            result?;
+        }
+        writer.flush()
+    }
+}